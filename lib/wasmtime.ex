@@ -4,10 +4,22 @@ defmodule Wasmtime do
   or a file path. Wasmtime will JIT compile, interpret and make it available. This
   Elixir module is backed by a GenServer for concurrency reasons and to keep state
   of the loaded instance.
+
+  An instance keeps its state in a single store, which a mutex guards. Calls to the same
+  instance therefore run one at a time, whichever function they call, while calls to
+  different instances run in parallel. To spread calls to pure functions over several
+  schedulers, load a `Wasmtime.Pool` of identical instances.
+
+  Instances sharing a store, because one imports another's memory or links to it, also
+  run one call at a time between them. A host function calling into an instance that
+  shares its caller's store waits on the store its caller holds, so the call is stuck
+  until the import times out.
   """
 
   use GenServer
+  require Logger
   alias Wasmtime.Native
+  alias Wasmtime.Caller
   alias Wasmtime.FromBytes
   alias Wasmtime.FromFile
 
@@ -23,16 +35,38 @@ defmodule Wasmtime do
 
   defp init_payload(payload) do
     payload = Map.put(payload, :id, System.unique_integer([:monotonic]))
+    watch_owner(payload.id)
 
     Map.put(
       payload,
       :imports,
       Enum.reduce(payload.func_imports, %{}, fn x, acc ->
-        Map.put(acc, System.unique_integer([:monotonic]), {elem(x, 0), elem(x, 1), elem(x, 2)})
+        Map.put(
+          acc,
+          System.unique_integer([:monotonic]),
+          {elem(x, 0), elem(x, 1), elem(x, 2), import_timeout(x, payload.import_timeout)}
+        )
       end)
     )
   end
 
+  defp import_timeout({_, _, _, opts}, default), do: Keyword.get(opts, :timeout, default)
+  defp import_timeout(_, default), do: default
+
+  # Unloads the native session once this GenServer goes down, so that wasm threads
+  # blocked on one of its host functions trap instead of waiting forever.
+  defp watch_owner(id) do
+    owner = self()
+
+    spawn(fn ->
+      ref = Process.monitor(owner)
+
+      receive do
+        {:DOWN, ^ref, :process, _, _} -> Native.unload(id)
+      end
+    end)
+  end
+
   defp pidref_encode(pid_ref) do
     pid_ref |> :erlang.term_to_binary() |> Base.encode64()
   end
@@ -46,68 +80,166 @@ defmodule Wasmtime do
     |> Enum.reverse()
   end
 
+  defp host_modules(payload = %{builtins: true}), do: ["wasmtime_ex" | payload.host_modules]
+  defp host_modules(payload), do: payload.host_modules
+
+  defp extern_imports_to_term(payload) do
+    memory_imports =
+      Enum.map(payload.memory_imports, fn
+        {pid, name} when is_pid(pid) -> {:export, GenServer.call(pid, {:id}), name}
+        limits -> limits
+      end)
+
+    links = Enum.map(payload.links, fn {name, pid} -> {name, GenServer.call(pid, {:id})} end)
+
+    {memory_imports, payload.global_imports, payload.table_imports, links}
+  end
+
+  defp import_timeouts_to_term(payload) do
+    Enum.map(Map.get(payload, :imports), fn
+      {id, {_, _, _, :infinity}} -> {id, nil}
+      {id, {_, _, _, timeout}} -> {id, timeout}
+    end)
+  end
+
   @impl true
-  def handle_call({:call_func, fn_name, params}, from, payload) do
+  def handle_call({:call_func, fn_name, params, unsigned}, from, payload) do
     payload = Map.put(payload, from |> pidref_encode, from)
 
-    Native.call_func(
-      Map.get(payload, :id),
-      self(),
-      from |> pidref_encode(),
-      fn_name,
-      params,
-      payload |> func_imports_to_term
-    )
+    called =
+      Native.call_func(
+        Map.get(payload, :id),
+        self(),
+        from |> pidref_encode(),
+        fn_name,
+        params,
+        unsigned
+      )
+
+    case called do
+      :ok -> {:noreply, payload}
+      error -> {:reply, error, Map.delete(payload, from |> pidref_encode)}
+    end
+  end
 
-    {:noreply, payload}
+  @impl true
+  def handle_call({:call_many, calls, continue_on_error, unsigned}, from, payload) do
+    payload = Map.put(payload, from |> pidref_encode, from)
+
+    called =
+      Native.call_many(
+        Map.get(payload, :id),
+        self(),
+        from |> pidref_encode(),
+        calls,
+        continue_on_error,
+        unsigned
+      )
+
+    case called do
+      :ok -> {:noreply, payload}
+      error -> {:reply, error, Map.delete(payload, from |> pidref_encode)}
+    end
   end
 
   @impl true
-  def handle_call({:call_func_xt, fn_name, params}, _from, payload) do
-    {:reply, Native.call_func_xt(Map.get(payload, :id), fn_name, params), payload}
+  def handle_call(
+        {:call_with_binaries, fn_name, params, abi, binary_result, unsigned},
+        from,
+        payload
+      ) do
+    payload = Map.put(payload, from |> pidref_encode, from)
+
+    called =
+      Native.call_with_binaries(
+        Map.get(payload, :id),
+        from |> pidref_encode(),
+        fn_name,
+        params,
+        abi,
+        {binary_result, unsigned}
+      )
+
+    case called do
+      :ok -> {:noreply, payload}
+      error -> {:reply, error, Map.delete(payload, from |> pidref_encode)}
+    end
   end
 
   @impl true
   def handle_call({:load_from}, from, payload) do
     payload = Map.put(payload, from |> pidref_encode, from)
 
-    {:ok, config_encoded} = payload.config |> Jason.encode()
-
-    case payload do
-      payload = %FromBytes{} ->
-        Native.load_from(
-          Map.get(payload, :id),
-          self(),
-          from |> pidref_encode(),
-          "",
-          payload.bytes |> :binary.bin_to_list(),
-          payload |> func_imports_to_term,
-          config_encoded
-        )
-
-      payload = %FromFile{} ->
-        Native.load_from(
-          Map.get(payload, :id),
-          self(),
-          from |> pidref_encode(),
-          payload.file_path,
-          [],
-          payload |> func_imports_to_term,
-          config_encoded
-        )
+    loaded =
+      case payload do
+        payload = %FromBytes{} ->
+          Native.load_from(
+            Map.get(payload, :id),
+            self(),
+            from |> pidref_encode(),
+            "",
+            payload.bytes |> :binary.bin_to_list(),
+            payload |> func_imports_to_term,
+            payload |> import_timeouts_to_term,
+            payload |> host_modules,
+            payload |> extern_imports_to_term,
+            payload.config
+          )
+
+        payload = %FromFile{} ->
+          Native.load_from(
+            Map.get(payload, :id),
+            self(),
+            from |> pidref_encode(),
+            payload.file_path,
+            [],
+            payload |> func_imports_to_term,
+            payload |> import_timeouts_to_term,
+            payload |> host_modules,
+            payload |> extern_imports_to_term,
+            payload.config
+          )
+      end
+
+    case loaded do
+      :ok -> {:noreply, payload}
+      error -> {:reply, error, Map.delete(payload, from |> pidref_encode)}
     end
-
-    {:noreply, payload}
   end
 
   @impl true
   def handle_call({:exports}, _from, payload) do
-    {:reply, Native.exports(payload.id, payload |> func_imports_to_term), payload}
+    {:reply, Native.exports(payload.id), payload}
+  end
+
+  @impl true
+  def handle_call({:describe}, _from, payload) do
+    {:reply, Native.describe(payload.id), payload}
+  end
+
+  @impl true
+  def handle_call({:module_bytes}, _from, payload = %FromBytes{}) do
+    {:reply, {:ok, payload.bytes}, payload}
+  end
+
+  @impl true
+  def handle_call({:module_bytes}, _from, payload = %FromFile{}) do
+    {:reply, File.read(payload.file_path), payload}
+  end
+
+  @impl true
+  def handle_call({:imports}, _from, payload) do
+    {:reply, Native.imports(payload.id), payload}
   end
 
   @impl true
   def handle_call({:get_func, fn_name}, _from, payload) do
-    {:reply, Native.get_func(payload.id, fn_name, payload |> func_imports_to_term), payload}
+    {:reply, Native.get_func(payload.id, fn_name), payload}
+  end
+
+  @impl true
+  def handle_call({:id}, _from, payload) do
+    {:reply, payload.id, payload}
   end
 
   @impl true
@@ -117,31 +249,76 @@ defmodule Wasmtime do
   end
 
   @impl true
-  def handle_info({:call_exfn, id, params}, payload) do
-    Native.exfn_reply(payload.id, id, invoke_import_res_ty(payload, id, params))
+  def handle_info({:call_exfn, id, call_id, params}, payload) do
+    Native.exfn_reply(payload.id, call_id, invoke_import_res_ty(payload, id, call_id, params))
+    {:noreply, payload}
+  end
+
+  @impl true
+  def handle_info({:wasm_log, level, msg}, payload) do
+    Logger.log(level, msg)
     {:noreply, payload}
   end
 
-  defp invoke_import_res_ty(payload, id, params) do
+  defp invoke_import_res_ty(payload, id, call_id, params) do
     func_t =
       Map.get(payload, :imports)
       |> Map.get(id)
 
-    Enum.zip([func_t |> elem(0) |> apply(params)], func_t |> elem(2))
+    fun = func_t |> elem(0)
+
+    params =
+      if is_function(fun, length(params) + 1) do
+        [%Caller{id: payload.id, call_id: call_id} | params]
+      else
+        params
+      end
+
+    Enum.zip([fun |> apply(params)], func_t |> elem(2))
   end
 
   defp _load(payload) do
     {:ok, pid} = GenServer.start_link(__MODULE__, payload)
 
     case GenServer.call(pid, {:load_from}) do
-      :ok -> {:ok, pid}
-      {:error, msg} -> {:error, msg}
+      :ok ->
+        {:ok, pid}
+
+      {:error, msg} ->
+        GenServer.stop(pid)
+        {:error, msg}
     end
   end
 
   @doc """
   Load a Wasm module given bytes in memory or from a Wasm file. Both `.wasm` and `.wat` files are supported.
 
+  Imported memories, globals and tables are provided by `memory_imports` (`{min, max}` pages),
+  `global_imports` (`{type, :const | :var, value}`) and `table_imports` (`{min, max}` funcref
+  elements), `max` being `nil` when unbounded. Like `func_imports`, each of them satisfies the
+  module's imports of its kind in order. A memory import can also be `{pid, export_name}`, which
+  shares the memory exported by another loaded instance. The importing instance then joins
+  that instance's store, so both see each other's writes. Since it's also compiled by that
+  instance's engine, its `config` has to be the same, or loading it fails.
+
+  `links` registers other loaded instances under a module name, as `{name, pid}`. Every import
+  from that module, whatever its kind, is then satisfied by the linked instance's export of
+  the same name, and takes no entry in the lists above. Linked instances share one store.
+
+  Each `func_imports` entry is either `{fun, params, results}` or `{fun, params, results, opts}`.
+  The `:timeout` option (in milliseconds or `:infinity`) overrides the payload's `import_timeout`
+  for that import. The default `import_timeout` of 4 seconds stays below the 5 seconds
+  `call_func/4` waits for a reply. When a host function doesn't reply in time, or this process goes down while
+  the call is waiting on it, the call traps with `{:error, {:host_timeout, import_name}}`.
+
+  A host function taking one extra first argument receives a `Wasmtime.Caller`, which gives it
+  access to the calling instance's memory and exports.
+
+  Imports provided by one of the native host modules named in `host_modules` are linked to it
+  instead, and don't take a `func_imports` entry. Setting `builtins: true` links the built-in
+  `"wasmtime_ex"` module, which provides `log(level, ptr, len)` forwarding to `Logger`,
+  `monotonic_now()` and `wall_now()` in nanoseconds, and `random_bytes(ptr, len)`.
+
   iex> {:ok, _pid} = Wasmtime.load(%Wasmtime.FromFile{file_path: "test/data/adder.wat"})
   """
   @spec load(%FromBytes{} | %FromFile{}) :: {atom(), pid()}
@@ -155,20 +332,150 @@ defmodule Wasmtime do
 
   @doc """
   Call a Wasm function.
+
+  Arguments are checked against the function's parameters before the call. Integers have
+  to fit the bit width of their parameter and floats have to be given as floats. Otherwise
+  the call returns `{:error, {:bad_arity, expected, got}}` or
+  `{:error, {:bad_argument, index, expected_type, got}}`, with `index` counted from 0. The
+  other ways of calling a function check their arguments the same way.
+
+  An integer argument can be given as signed or unsigned, as long as it fits the bit width
+  of its parameter. Integer results are read as signed, unless the `unsigned: true` option
+  is given:
+
+  iex> {:ok, pid} = Wasmtime.load(%Wasmtime.FromFile{file_path: "test/data/adder.wat"})
+  iex> Wasmtime.call_func(pid, "add", [4_294_967_295, 0])
+  {:ok, [-1]}
+  iex> Wasmtime.call_func(pid, "add", [-1, 0], unsigned: true)
+  {:ok, [4_294_967_295]}
+  """
+  @spec call_func(pid(), String.t(), list(), keyword()) :: {atom(), list()}
+  def call_func(pid, fn_name, params \\ [], opts \\ [])
+      when is_pid(pid) and is_bitstring(fn_name) and is_list(params) and is_list(opts) do
+    GenServer.call(pid, {:call_func, fn_name, params, unsigned?(opts)})
+  end
+
+  defp unsigned?(opts), do: Keyword.get(opts, :unsigned, false)
+
+  @doc """
+  Call several Wasm functions of an instance in order, in a single round-trip to the
+  native code. No other call to the instance runs in between. Returns a result per call,
+  as `call_func/4` would.
+
+  A call that traps stops the batch, leaving out the results of the calls after it, unless
+  the `on_trap: :continue` option is given. Every function and its arguments are checked
+  before any of them runs, and the first call that doesn't check returns
+  `{:error, {index, reason}}`. The `unsigned` option applies to every call.
+
+  iex> {:ok, pid} = Wasmtime.load(%Wasmtime.FromFile{file_path: "test/data/adder.wat"})
+  iex> Wasmtime.call_many(pid, [{"add", [1, 2]}, {"add", [3, 4]}])
+  {:ok, [{:ok, [3]}, {:ok, [7]}]}
   """
-  @spec call_func(pid(), String.t(), list()) :: {atom(), list()}
-  def call_func(pid, fn_name, params \\ [])
-      when is_pid(pid) and is_bitstring(fn_name) and is_list(params) do
-    GenServer.call(pid, {:call_func, fn_name, params})
+  @spec call_many(pid(), list({String.t(), list()}), keyword()) :: {atom(), any()}
+  def call_many(pid, calls, opts \\ []) when is_pid(pid) and is_list(calls) and is_list(opts) do
+    continue_on_error =
+      case Keyword.get(opts, :on_trap, :stop) do
+        :stop -> false
+        :continue -> true
+      end
+
+    GenServer.call(pid, {:call_many, calls, continue_on_error, unsigned?(opts)})
   end
 
   @doc """
-  Call a Wasm function without using threads for specific low latency use cases. This function should only be used if you really have to save some extra microseconds, and the Wasm function is lightweight (takes less than < 1ms to execute). Also, the Wasm module can't have any imports when using this function.
+  Call a Wasm function passing binaries, such as strings, through the guest's memory.
+
+  Each binary argument is copied into a buffer the guest allocates, and passed as two
+  `i32` parameters: its pointer and its length. The buffers are freed once the function
+  returns. With the `returns: :binary` option, the function returns a pointer and a
+  length as well, either as two `i32` results or packed in an `i64` with the pointer in
+  its high 32 bits, and the call returns `{:ok, binary}` with the bytes they point to,
+  which are then freed too. Other arguments and results are handled as by `call_func/4`.
+
+  The guest has to export its memory and two functions, which can be named with options:
+
+    * `alloc: "alloc"` takes a length and returns a pointer to that many bytes.
+    * `dealloc: "dealloc"` takes a pointer and a length, and frees the bytes.
+    * `memory: "memory"` is the memory pointers refer to.
+
+  A Rust guest can implement them as:
+
+  ```
+  #[no_mangle]
+  pub extern "C" fn alloc(len: usize) -> *mut u8 {
+      let mut buf = Vec::with_capacity(len);
+      let ptr = buf.as_mut_ptr();
+      std::mem::forget(buf);
+      ptr
+  }
+
+  #[no_mangle]
+  pub unsafe extern "C" fn dealloc(ptr: *mut u8, len: usize) {
+      drop(Vec::from_raw_parts(ptr, 0, len));
+  }
+  ```
+
+  and return bytes it leaks with `std::mem::forget` as `((ptr as u64) << 32) | len as u64`.
+  """
+  @spec call_with_binaries(pid(), String.t(), list(), keyword()) :: {atom(), list() | binary()}
+  def call_with_binaries(pid, fn_name, params, opts \\ [])
+      when is_pid(pid) and is_bitstring(fn_name) and is_list(params) and is_list(opts) do
+    abi =
+      {Keyword.get(opts, :alloc, "alloc"), Keyword.get(opts, :dealloc, "dealloc"),
+       Keyword.get(opts, :memory, "memory")}
+
+    binary_result =
+      case Keyword.get(opts, :returns, :values) do
+        :values -> false
+        :binary -> true
+      end
+
+    GenServer.call(
+      pid,
+      {:call_with_binaries, fn_name, params, abi, binary_result, unsigned?(opts)}
+    )
+  end
+
+  @doc """
+  Call a Wasm function without using threads for specific low latency use cases. This function should only be used if you really have to save some extra microseconds, and the Wasm function is lightweight (takes less than < 1ms to execute). Its imports should be native host functions, such as the builtins, since waiting on an Elixir host function defeats the purpose.
+
+  The call runs in the calling process rather than in the instance's GenServer, which stays
+  free to serve other requests meanwhile. For a function called repeatedly, a handle from
+  `func_handle/2` also saves looking it up on every call.
+
+  Rather than wait for another call on the same instance (or store) to return, it fails
+  with `{:error, :busy}`, since waiting would hold a dirty scheduler meanwhile.
   """
-  @spec call_func_xt(pid(), String.t(), list()) :: {atom(), list()}
-  def call_func_xt(pid, fn_name, params \\ [])
-      when is_pid(pid) and is_bitstring(fn_name) and is_list(params) do
-    GenServer.call(pid, {:call_func_xt, fn_name, params})
+  @spec call_func_xt(pid(), String.t(), list(), keyword()) :: {atom(), list() | :busy}
+  def call_func_xt(pid, fn_name, params \\ [], opts \\ [])
+      when is_pid(pid) and is_bitstring(fn_name) and is_list(params) and is_list(opts) do
+    Native.call_func_xt(GenServer.call(pid, {:id}), fn_name, params, unsigned?(opts))
+  end
+
+  @doc """
+  Look up an exported function once and return a handle to it, which caches the function
+  and its signature. Calling it with `call_handle/3` skips the lookup by name.
+
+  iex> {:ok, pid} = Wasmtime.load(%Wasmtime.FromFile{file_path: "test/data/adder.wat"})
+  iex> {:ok, add} = Wasmtime.func_handle(pid, "add")
+  iex> Wasmtime.call_handle(add, [1, 2])
+  {:ok, [3]}
+  """
+  @spec func_handle(pid(), String.t()) :: {:ok, reference()} | {:error, String.t()}
+  def func_handle(pid, fn_name) when is_pid(pid) and is_bitstring(fn_name) do
+    Native.func_handle(GenServer.call(pid, {:id}), fn_name)
+  end
+
+  @doc """
+  Call the function behind a handle returned by `func_handle/2`. Like `call_func_xt/4`,
+  the call runs in the calling process, so it's meant for lightweight functions called
+  on a hot path, and fails with `{:error, :busy}` while another call holds the instance's
+  store. It fails once the instance is unloaded.
+  """
+  @spec call_handle(reference(), list(), keyword()) :: {atom(), list() | :busy}
+  def call_handle(handle, params \\ [], opts \\ [])
+      when is_reference(handle) and is_list(params) and is_list(opts) do
+    Native.call_handle(handle, params, unsigned?(opts))
   end
 
   @doc """
@@ -179,6 +486,98 @@ defmodule Wasmtime do
     GenServer.call(pid, {:exports})
   end
 
+  @doc """
+  List all exports with their full types, as `{name, kind, type}`. `type` is a map of
+  `params` and `results` for functions, `type` and `mutability` for globals, `min`, `max`
+  (in pages), `shared` and `memory64` for memories, and `element`, `min` and `max` for tables.
+
+  Given a `Wasmtime.FromBytes` or `Wasmtime.FromFile` payload, the module is compiled but
+  isn't instantiated.
+
+  iex> {:ok, pid} = Wasmtime.load(%Wasmtime.FromFile{file_path: "test/data/adder.wat"})
+  iex> Wasmtime.describe(pid)
+  {:ok, [{"add", :func, %{params: [:i32, :i32], results: [:i32]}}]}
+  """
+  @spec describe(pid() | %FromBytes{} | %FromFile{}) ::
+          {:ok, list({String.t(), atom(), map()})} | {:error, String.t()}
+  def describe(pid) when is_pid(pid) do
+    GenServer.call(pid, {:describe})
+  end
+
+  def describe(payload = %FromBytes{}) do
+    Native.module_describe("", payload.bytes |> :binary.bin_to_list(), payload.config)
+  end
+
+  def describe(payload = %FromFile{}) do
+    Native.module_describe(payload.file_path, [], payload.config)
+  end
+
+  @doc """
+  Validate Wasm bytes against the features enabled by `config`, without compiling them
+  or starting an instance. On failure, `offset` is the position in the bytes where
+  validation failed, or `nil` when it isn't known.
+
+  iex> Wasmtime.validate(<<0, 97, 115, 109, 1, 0, 0, 0>>)
+  :ok
+  """
+  @spec validate(binary(), %Wasmtime.Config{}) ::
+          :ok | {:error, %{offset: non_neg_integer() | nil, message: String.t()}}
+  def validate(bytes, config \\ %Wasmtime.Config{}) when is_binary(bytes) do
+    Native.validate(bytes, config)
+  end
+
+  @doc """
+  Convert WAT text into its Wasm binary encoding. Parse errors report the line and column
+  they were found at, starting from 1.
+
+  iex> Wasmtime.wat2wasm("(module)")
+  {:ok, <<0, 97, 115, 109, 1, 0, 0, 0>>}
+  """
+  @spec wat2wasm(String.t()) ::
+          {:ok, binary()}
+          | {:error, %{line: pos_integer(), column: pos_integer(), message: String.t()}}
+  def wat2wasm(text) when is_binary(text) do
+    Native.wat2wasm(text)
+  end
+
+  @doc """
+  Print Wasm bytes as WAT text.
+
+  iex> {:ok, "(module" <> _} = Wasmtime.wasm2wat(<<0, 97, 115, 109, 1, 0, 0, 0>>)
+  """
+  @spec wasm2wat(binary()) :: {:ok, String.t()} | {:error, String.t()}
+  def wasm2wat(bytes) when is_binary(bytes) do
+    Native.wasm2wat(bytes)
+  end
+
+  @doc """
+  Size the native pool that runs `load/1` and `call_func/4`. `pool_size` is how many of
+  them may execute at once, and `queue_depth` how many more may wait for a free slot.
+  Beyond that, they return `{:error, :overloaded}`. Omitted options are left unchanged.
+  The defaults are 1024 and 4096.
+  """
+  @spec configure_pool(pool_size: pos_integer(), queue_depth: non_neg_integer()) ::
+          :ok | {:error, String.t()}
+  def configure_pool(opts) when is_list(opts) do
+    Native.configure_pool(Keyword.get(opts, :pool_size), Keyword.get(opts, :queue_depth))
+  end
+
+  @doc """
+  Current state of the native pool: the `active` executions, those `queued` waiting for a
+  slot, and the configured `pool_size` and `queue_depth`.
+
+  iex> %{active: _, queued: _, pool_size: _, queue_depth: _} = Wasmtime.pool_stats()
+  """
+  @spec pool_stats() :: %{
+          active: non_neg_integer(),
+          queued: non_neg_integer(),
+          pool_size: pos_integer(),
+          queue_depth: non_neg_integer()
+        }
+  def pool_stats() do
+    Native.pool_stats()
+  end
+
   @doc """
   Get an exported Wasm function.
   """
@@ -0,0 +1,48 @@
+defmodule Wasmtime.Caller do
+  @moduledoc """
+  The context of the Wasm instance calling a host function. A host function receives it as
+  its first argument when its arity is the number of import params plus one. A caller is
+  only valid while the import invocation it was given to is running.
+  """
+
+  alias Wasmtime.Native
+
+  @enforce_keys [:id, :call_id]
+  defstruct id: nil, call_id: nil
+
+  @typedoc """
+  Wasmtime.Caller
+  """
+  @type t() :: %__MODULE__{
+          id: integer(),
+          call_id: pos_integer()
+        }
+
+  @doc """
+  Read `len` bytes at `offset` of the caller's exported `"memory"`.
+  """
+  @spec read_memory(t(), non_neg_integer(), non_neg_integer()) ::
+          {:ok, binary()} | {:error, String.t()}
+  def read_memory(caller = %__MODULE__{}, offset, len)
+      when is_integer(offset) and offset >= 0 and is_integer(len) and len >= 0 do
+    Native.caller_read_memory(caller.id, caller.call_id, offset, len)
+  end
+
+  @doc """
+  Write `data` at `offset` of the caller's exported `"memory"`.
+  """
+  @spec write_memory(t(), non_neg_integer(), binary()) :: :ok | {:error, String.t()}
+  def write_memory(caller = %__MODULE__{}, offset, data)
+      when is_integer(offset) and offset >= 0 and is_binary(data) do
+    Native.caller_write_memory(caller.id, caller.call_id, offset, data)
+  end
+
+  @doc """
+  Get the kind of one of the caller's exports.
+  """
+  @spec get_export(t(), String.t()) ::
+          {:ok, :func | :global | :table | :memory} | {:error, String.t()}
+  def get_export(caller = %__MODULE__{}, name) when is_bitstring(name) do
+    Native.caller_get_export(caller.id, caller.call_id, name)
+  end
+end
@@ -1,13 +1,52 @@
 defmodule Wasmtime.Config do
   @moduledoc """
   Struct for configuring Wasmtime options.
+
+  The `simd`, `bulk_memory`, `reference_types`, `multi_value`, `multi_memory`, `memory64`,
+  `threads`, `relaxed_simd` and `tail_call` toggles enable or disable the Wasm proposals of
+  the same name. They default to what Wasmtime enables. `relaxed_simd` and `tail_call` aren't
+  supported yet, and enabling either fails to load.
+
+  The memory sizing fields, in bytes, map to Wasmtime's options of the same name and keep
+  Wasmtime's defaults when `nil`. `cranelift_flags` are `{name, value}` pairs passed to
+  Cranelift's settings as is. A wrong flag can make Cranelift generate unsound code, breaking
+  the sandbox, so they must never come from untrusted input.
+
+  `deterministic: true` makes runs repeatable across hosts: NaNs are canonicalized, `threads`
+  can't be enabled, and the built-in `"wasmtime_ex"` host module's clocks
+  become virtual, advancing by a fixed tick on every read, while `random_bytes` is seeded with
+  `deterministic_seed`.
+
+  `interruptable` has no effect, and is only kept so that configs setting it still compile.
+
+  Every other field is checked when the config is used, and an unsupported value fails with
+  `{:error, {:invalid_config, field, value}}`.
   """
-  @derive Jason.Encoder
   defstruct debug_info: false,
             interruptable: false,
             max_wasm_stack: Bitwise.<<<(1, 20),
             strategy: :auto,
-            cranelift_opt_level: :none
+            cranelift_opt_level: :none,
+            simd: true,
+            bulk_memory: true,
+            reference_types: true,
+            multi_value: true,
+            multi_memory: false,
+            memory64: false,
+            threads: false,
+            relaxed_simd: false,
+            tail_call: false,
+            static_memory_maximum_size: nil,
+            static_memory_guard_size: nil,
+            dynamic_memory_guard_size: nil,
+            dynamic_memory_reserved_for_growth: nil,
+            parallel_compilation: true,
+            cranelift_nan_canonicalization: false,
+            generate_address_map: true,
+            native_unwind_info: true,
+            cranelift_flags: [],
+            deterministic: false,
+            deterministic_seed: 0
 
   @typedoc """
   Wasmtime.Config
@@ -16,7 +55,27 @@ defmodule Wasmtime.Config do
           debug_info: boolean(),
           interruptable: boolean(),
           max_wasm_stack: pos_integer(),
-          strategy: :auto | :cranelift | :lightbeam,
-          cranelift_opt_level: :none | :speed | :speed_and_size
+          strategy: :auto | :cranelift,
+          cranelift_opt_level: :none | :speed | :speed_and_size,
+          simd: boolean(),
+          bulk_memory: boolean(),
+          reference_types: boolean(),
+          multi_value: boolean(),
+          multi_memory: boolean(),
+          memory64: boolean(),
+          threads: boolean(),
+          relaxed_simd: boolean(),
+          tail_call: boolean(),
+          static_memory_maximum_size: non_neg_integer() | nil,
+          static_memory_guard_size: non_neg_integer() | nil,
+          dynamic_memory_guard_size: non_neg_integer() | nil,
+          dynamic_memory_reserved_for_growth: non_neg_integer() | nil,
+          parallel_compilation: boolean(),
+          cranelift_nan_canonicalization: boolean(),
+          generate_address_map: boolean(),
+          native_unwind_info: boolean(),
+          cranelift_flags: list({String.t(), String.t()}),
+          deterministic: boolean(),
+          deterministic_seed: non_neg_integer()
         }
 end
@@ -6,7 +6,16 @@ defmodule Wasmtime.FromBytes do
   alias Wasmtime.Config, as: Config
 
   @enforce_keys [:bytes]
-  defstruct bytes: nil, func_imports: [], config: %Config{}
+  defstruct bytes: nil,
+            func_imports: [],
+            memory_imports: [],
+            global_imports: [],
+            table_imports: [],
+            links: [],
+            import_timeout: 4_000,
+            host_modules: [],
+            builtins: false,
+            config: %Config{}
 
   @typedoc """
   Wasmtime.FromBytes
@@ -14,6 +23,13 @@ defmodule Wasmtime.FromBytes do
   @type t() :: %__MODULE__{
           bytes: nonempty_charlist(),
           func_imports: list(),
+          memory_imports: list({non_neg_integer(), non_neg_integer() | nil} | {pid(), String.t()}),
+          global_imports: list({atom(), :const | :var, number()}),
+          table_imports: list({non_neg_integer(), non_neg_integer() | nil}),
+          links: list({String.t(), pid()}),
+          import_timeout: timeout(),
+          host_modules: list(String.t()),
+          builtins: boolean(),
           config: %Config{}
         }
 end
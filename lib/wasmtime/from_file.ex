@@ -7,7 +7,16 @@ defmodule Wasmtime.FromFile do
   alias Wasmtime.Config, as: Config
 
   @enforce_keys [:file_path]
-  defstruct file_path: nil, func_imports: [], config: %Config{}
+  defstruct file_path: nil,
+            func_imports: [],
+            memory_imports: [],
+            global_imports: [],
+            table_imports: [],
+            links: [],
+            import_timeout: 4_000,
+            host_modules: [],
+            builtins: false,
+            config: %Config{}
 
   @typedoc """
   Wasmtime.FromFile
@@ -15,6 +24,13 @@ defmodule Wasmtime.FromFile do
   @type t() :: %__MODULE__{
           file_path: String.t(),
           func_imports: list(),
+          memory_imports: list({non_neg_integer(), non_neg_integer() | nil} | {pid(), String.t()}),
+          global_imports: list({atom(), :const | :var, number()}),
+          table_imports: list({non_neg_integer(), non_neg_integer() | nil}),
+          links: list({String.t(), pid()}),
+          import_timeout: timeout(),
+          host_modules: list(String.t()),
+          builtins: boolean(),
           config: %Config{}
         }
 end
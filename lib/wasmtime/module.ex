@@ -0,0 +1,74 @@
+defmodule Wasmtime.Module do
+  @moduledoc """
+  Introspection of Wasm modules, loaded or not.
+  """
+
+  alias Wasmtime.Native
+  alias Wasmtime.FromBytes
+  alias Wasmtime.FromFile
+
+  @type import_type() ::
+          {list(atom()), list(atom())}
+          | {atom(), :const | :var}
+          | {non_neg_integer(), non_neg_integer() | nil}
+          | {atom(), non_neg_integer(), non_neg_integer() | nil}
+
+  @doc """
+  List a module's imports as `{module, name, kind, type}`, in the order they're declared.
+  `kind` is one of `:func`, `:global`, `:memory` or `:table`, and `type` is `{params, results}`
+  for functions, `{type, :const | :var}` for globals, `{min, max}` pages for memories and
+  `{element_type, min, max}` for tables.
+
+  Given a `Wasmtime.FromBytes` or `Wasmtime.FromFile` payload, the module is only parsed,
+  neither compiled nor instantiated, so its imports don't need to be provided.
+
+  iex> {:ok, [{"env", "imported_func", :func, {[:i32], []}}]} =
+  ...>   Wasmtime.Module.imports(%Wasmtime.FromBytes{
+  ...>     bytes: ~S/(module (import "env" "imported_func" (func (param i32))))/
+  ...>   })
+  """
+  @spec imports(pid() | %FromBytes{} | %FromFile{}) ::
+          {:ok, list({String.t(), String.t(), atom(), import_type()})} | {:error, String.t()}
+  def imports(pid) when is_pid(pid) do
+    GenServer.call(pid, {:imports})
+  end
+
+  def imports(payload = %FromBytes{}) do
+    Native.module_imports("", payload.bytes |> :binary.bin_to_list())
+  end
+
+  def imports(payload = %FromFile{}) do
+    Native.module_imports(payload.file_path, [])
+  end
+
+  @doc """
+  Get the payloads of the custom sections named `name`, in the order they appear in the
+  module. Loaded instances are read from the bytes or file they were loaded from.
+
+  iex> {:ok, []} =
+  ...>   Wasmtime.Module.custom_sections(%Wasmtime.FromBytes{bytes: ~S/(module)/}, "abi")
+  """
+  @spec custom_sections(pid() | %FromBytes{} | %FromFile{}, String.t()) ::
+          {:ok, list(binary())} | {:error, String.t()}
+  def custom_sections(module, name) when is_binary(name) do
+    with {:ok, bytes} <- module_bytes(module) do
+      Native.custom_sections(bytes, name)
+    end
+  end
+
+  @doc """
+  Get the function names recorded in the module's `name` custom section, as a map from
+  function index to name. Imported functions come first in the index space.
+  """
+  @spec function_names(pid() | %FromBytes{} | %FromFile{}) ::
+          {:ok, %{non_neg_integer() => String.t()}} | {:error, String.t()}
+  def function_names(module) do
+    with {:ok, bytes} <- module_bytes(module) do
+      Native.function_names(bytes)
+    end
+  end
+
+  defp module_bytes(pid) when is_pid(pid), do: GenServer.call(pid, {:module_bytes})
+  defp module_bytes(payload = %FromBytes{}), do: {:ok, payload.bytes}
+  defp module_bytes(payload = %FromFile{}), do: File.read(payload.file_path)
+end
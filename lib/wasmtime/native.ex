@@ -5,17 +5,69 @@ defmodule Wasmtime.Native do
   """
   use Rustler, otp_app: :wasmtime, crate: "wasmtime_ex"
 
-  def load_from(_id, _gen_pid, _from_pid, _file_name, _bin, _func_ids, _config),
+  def load_from(
+        _id,
+        _gen_pid,
+        _from_pid,
+        _file_name,
+        _bin,
+        _func_ids,
+        _import_timeouts,
+        _host_modules,
+        _extern_imports,
+        _config
+      ),
+      do: :erlang.nif_error(:nif_not_loaded)
+
+  def call_func(_id, _gen_pid, _from_pid, _func_name, _params, _unsigned),
+    do: :erlang.nif_error(:nif_not_loaded)
+
+  def call_func_xt(_id, _func_name, _params, _unsigned),
     do: :erlang.nif_error(:nif_not_loaded)
 
-  def call_func(_id, _gen_pid, _from_pid, _func_name, _params, _func_imports),
+  def call_many(_id, _gen_pid, _from_pid, _calls, _continue_on_error, _unsigned),
     do: :erlang.nif_error(:nif_not_loaded)
 
-  def call_func_xt(_id, _func_name, _params), do: :erlang.nif_error(:nif_not_loaded)
+  def call_with_binaries(_id, _from_pid, _func_name, _params, _abi, _opts),
+    do: :erlang.nif_error(:nif_not_loaded)
+
+  def func_handle(_id, _func_name), do: :erlang.nif_error(:nif_not_loaded)
+
+  def call_handle(_handle, _params, _unsigned), do: :erlang.nif_error(:nif_not_loaded)
+
+  def exfn_reply(_id, _call_id, _results), do: :erlang.nif_error(:nif_not_loaded)
+
+  def get_func(_id, _func_name), do: :erlang.nif_error(:nif_not_loaded)
+
+  def exports(_id), do: :erlang.nif_error(:nif_not_loaded)
+
+  def imports(_id), do: :erlang.nif_error(:nif_not_loaded)
+
+  def module_imports(_file_name, _bin), do: :erlang.nif_error(:nif_not_loaded)
+
+  def describe(_id), do: :erlang.nif_error(:nif_not_loaded)
+
+  def module_describe(_file_name, _bin, _config), do: :erlang.nif_error(:nif_not_loaded)
+
+  def validate(_bin, _config), do: :erlang.nif_error(:nif_not_loaded)
+
+  def wat2wasm(_text), do: :erlang.nif_error(:nif_not_loaded)
+
+  def wasm2wat(_bin), do: :erlang.nif_error(:nif_not_loaded)
+
+  def custom_sections(_bin, _name), do: :erlang.nif_error(:nif_not_loaded)
+
+  def function_names(_bin), do: :erlang.nif_error(:nif_not_loaded)
+
+  def configure_pool(_pool_size, _queue_depth), do: :erlang.nif_error(:nif_not_loaded)
+
+  def pool_stats(), do: :erlang.nif_error(:nif_not_loaded)
+
+  def unload(_id), do: :erlang.nif_error(:nif_not_loaded)
 
-  def exfn_reply(_id, _func_id, _results), do: :erlang.nif_error(:nif_not_loaded)
+  def caller_read_memory(_id, _call_id, _offset, _len), do: :erlang.nif_error(:nif_not_loaded)
 
-  def get_func(_id, _func_name, _func_imports), do: :erlang.nif_error(:nif_not_loaded)
+  def caller_write_memory(_id, _call_id, _offset, _data), do: :erlang.nif_error(:nif_not_loaded)
 
-  def exports(_id, _func_imports), do: :erlang.nif_error(:nif_not_loaded)
+  def caller_get_export(_id, _call_id, _name), do: :erlang.nif_error(:nif_not_loaded)
 end
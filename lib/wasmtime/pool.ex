@@ -0,0 +1,102 @@
+defmodule Wasmtime.Pool do
+  @moduledoc """
+  A fixed number of identical instances of the same module, which calls are spread over
+  in a round-robin fashion. Each instance has its own store, so calls are only
+  serialized per instance, and functions that don't depend on state changed by previous
+  calls scale with the pool size.
+
+  Picking an instance is lock-free and doesn't go through any process. The instances' ids
+  are looked up once at load, so `call_func_xt/4` doesn't either.
+
+  iex> {:ok, pool} = Wasmtime.Pool.load(%Wasmtime.FromFile{file_path: "test/data/adder.wat"}, 4)
+  iex> Wasmtime.Pool.call_func(pool, "add", [1, 2])
+  {:ok, [3]}
+  """
+
+  alias Wasmtime.FromBytes
+  alias Wasmtime.FromFile
+
+  @enforce_keys [:pids, :ids, :counter]
+  defstruct pids: {}, ids: {}, counter: nil
+
+  @typedoc """
+  Wasmtime.Pool
+  """
+  @type t() :: %__MODULE__{
+          pids: tuple(),
+          ids: tuple(),
+          counter: :atomics.atomics_ref()
+        }
+
+  @doc """
+  Load `size` instances of the payload's module. If any of them fails to load, the ones
+  already loaded are stopped.
+  """
+  @spec load(%FromBytes{} | %FromFile{}, pos_integer()) :: {:ok, t()} | {:error, any()}
+  def load(payload, size) when is_integer(size) and size > 0 do
+    loaded =
+      Enum.reduce_while(1..size, {:ok, []}, fn _, {:ok, pids} ->
+        case Wasmtime.load(payload) do
+          {:ok, pid} -> {:cont, {:ok, [pid | pids]}}
+          error -> {:halt, {error, pids}}
+        end
+      end)
+
+    case loaded do
+      {:ok, pids} ->
+        pids = Enum.reverse(pids)
+        ids = Enum.map(pids, &GenServer.call(&1, {:id}))
+
+        {:ok,
+         %__MODULE__{
+           pids: List.to_tuple(pids),
+           ids: List.to_tuple(ids),
+           counter: counter()
+         }}
+
+      {error, pids} ->
+        Enum.each(pids, &GenServer.stop/1)
+        error
+    end
+  end
+
+  @doc """
+  Call a Wasm function on the next instance of the pool.
+  """
+  @spec call_func(t(), String.t(), list(), keyword()) :: {atom(), list()}
+  def call_func(pool = %__MODULE__{}, fn_name, params \\ [], opts \\ []) do
+    Wasmtime.call_func(elem(pool.pids, next(pool)), fn_name, params, opts)
+  end
+
+  @doc """
+  Call a Wasm function on the next instance of the pool, like `Wasmtime.call_func_xt/4`.
+  """
+  @spec call_func_xt(t(), String.t(), list(), keyword()) :: {atom(), list() | :busy}
+  def call_func_xt(pool = %__MODULE__{}, fn_name, params \\ [], opts \\ []) do
+    unsigned = Keyword.get(opts, :unsigned, false)
+    Wasmtime.Native.call_func_xt(elem(pool.ids, next(pool)), fn_name, params, unsigned)
+  end
+
+  @doc """
+  The pids of the pool's instances.
+  """
+  @spec pids(t()) :: list(pid())
+  def pids(pool = %__MODULE__{}), do: Tuple.to_list(pool.pids)
+
+  @doc """
+  Stop all of the pool's instances.
+  """
+  @spec stop(t()) :: :ok
+  def stop(pool = %__MODULE__{}) do
+    pool |> pids() |> Enum.each(&GenServer.stop/1)
+  end
+
+  defp counter() do
+    :atomics.new(1, signed: false)
+  end
+
+  defp next(pool) do
+    i = :atomics.add_get(pool.counter, 1, 1)
+    rem(i, tuple_size(pool.pids))
+  end
+end
@@ -5,7 +5,7 @@ defmodule Wasmtime.MixProject do
     [
       app: :wasmtime,
       version: "0.3.0",
-      elixir: "~> 1.10",
+      elixir: "~> 1.11",
       start_permanent: Mix.env() == :prod,
       compilers: Mix.compilers(),
       name: "Wasmtime",
@@ -39,7 +39,6 @@ defmodule Wasmtime.MixProject do
   defp deps do
     [
       {:rustler, "~> 0.26.0"},
-      {:jason, "~> 1.2.2"},
       {:excoveralls, "~> 0.13.2", only: :test},
       {:ex_doc, "~> 0.23", only: :dev, runtime: false},
       {:benchee, "~> 1.0", only: :dev}
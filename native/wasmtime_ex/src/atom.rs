@@ -10,10 +10,46 @@ rustler::atoms! {
     extern_ref,
     func_ref,
 
+    const_ = "const",
+    var,
+
     func,
     global,
     table,
     memory,
+    export,
+
+    params,
+    results,
+    type_ = "type",
+    mutability,
+    min,
+    max,
+    shared,
+    memory64,
+    element,
+
+    offset,
+    message,
+    line,
+    column,
+
+    invalid_config,
+
+    overloaded,
+    busy,
+    active,
+    queued,
+    pool_size,
+    queue_depth,
+    bad_arity,
+    bad_argument,
     call_exfn,
-    gen_reply
+    gen_reply,
+    host_timeout,
+    wasm_log,
+
+    debug,
+    info,
+    warning
 }
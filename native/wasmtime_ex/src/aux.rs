@@ -1,34 +1,24 @@
 use crate::atom;
 use crate::config;
+use crate::host::{self, HostContext};
 use crate::session::SESSIONS;
 
-use crate::session::SVal;
+use crate::session::{
+    self, CallerReply, CallerRequest, ExternImports, FuncHandle, FuncImport, HostMsg, MemoryImport,
+    PendingCalls, SVal, Session,
+};
 use rustler::Error as RustlerError;
-use rustler::{Atom, Encoder, Env, OwnedEnv, LocalPid, Term};
+use rustler::{Atom, Binary, Encoder, Env, LocalPid, NifTuple, OwnedEnv, Term};
 use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 use wasmtime::*;
 
-pub fn imports_valtype_to_extern(
-    fn_imports: Vec<(i64, Vec<ValType>, Vec<ValType>)>,
-    store: &mut Store<()>
-) -> Vec<Extern> {
-    let mut _func_imports: Vec<Extern> = Vec::with_capacity(fn_imports.len());
-    let mut _store = store;
-    for (_, func_params, func_results) in fn_imports {
-        let fun: Extern = Func::new(
-            &mut _store,
-            FuncType::new(func_params.into_iter(), func_results.into_iter()),
-            move |_, _, _| Ok(()),
-        )
-        .into();
-        _func_imports.push(fun);
-    }
-    _func_imports
-}
-
 pub fn imports_term_to_valtype(
-    func_imports: &Vec<(i64, Vec<Atom>, Vec<Atom>)>
+    func_imports: &Vec<(i64, Vec<Atom>, Vec<Atom>)>,
 ) -> Result<Vec<(i64, Vec<ValType>, Vec<ValType>)>, Box<dyn Error>> {
     let mut fn_imports: Vec<(i64, Vec<ValType>, Vec<ValType>)> =
         Vec::with_capacity(func_imports.len());
@@ -58,6 +48,114 @@ pub fn imports_term_to_valtype(
     Ok(fn_imports)
 }
 
+/// Encodes the scalar values of `vals`, skipping references and reading
+/// integers as unsigned if `unsigned` is set.
+pub fn vals_to_terms<'a>(env: Env<'a>, vals: &[Val], unsigned: bool) -> Vec<Term<'a>> {
+    let mut res: Vec<Term> = Vec::with_capacity(vals.len());
+    for val in vals {
+        match val {
+            Val::I32(v) if unsigned => res.push((*v as u32).encode(env)),
+            Val::I64(v) if unsigned => res.push((*v as u64).encode(env)),
+            Val::I32(v) => res.push(v.encode(env)),
+            Val::I64(v) => res.push(v.encode(env)),
+            Val::F32(v) => res.push(f32::from_bits(*v).encode(env)),
+            Val::F64(v) => res.push(f64::from_bits(*v).encode(env)),
+            _ => (),
+        };
+    }
+    res
+}
+
+/// Calls `func_name` on the session's instance once no other call holds its store.
+pub async fn call_session_func(
+    session: &Session,
+    func_name: &str,
+    params: &[Val],
+) -> anyhow::Result<Vec<Val>> {
+    let mut store = session.store.lock().await;
+    call_instance_func(&mut *store, &session.instance, func_name, params).await
+}
+
+/// Runs `calls` in order on the session's instance, holding its store for the
+/// whole batch so no other call runs in between. Stops after the first call
+/// that fails unless `continue_on_error` is set.
+pub async fn call_session_funcs(
+    session: &Session,
+    calls: &[(String, Vec<Val>)],
+    continue_on_error: bool,
+) -> Vec<anyhow::Result<Vec<Val>>> {
+    let mut store = session.store.lock().await;
+    let mut results = Vec::with_capacity(calls.len());
+    for (func_name, params) in calls {
+        let called = call_instance_func(&mut *store, &session.instance, func_name, params).await;
+        let failed = called.is_err();
+        results.push(called);
+        if failed && !continue_on_error {
+            break;
+        }
+    }
+    results
+}
+
+async fn call_instance_func(
+    store: &mut Store<()>,
+    instance: &Instance,
+    func_name: &str,
+    params: &[Val],
+) -> anyhow::Result<Vec<Val>> {
+    let func = match instance.get_func(&mut *store, func_name) {
+        Some(v) => v,
+        None => {
+            return Err(anyhow::Error::msg(std::format!(
+                "function {:?} not found",
+                func_name
+            )))
+        }
+    };
+    let mut results = default_vals(func.ty(&*store).results());
+    func.call_async(&mut *store, params, &mut results).await?;
+    Ok(results)
+}
+
+/// Like `call_session_func`, except that it returns `None` rather than wait
+/// when another call holds the store.
+pub async fn try_call_session_func(
+    session: &Session,
+    func_name: &str,
+    params: &[Val],
+) -> Option<anyhow::Result<Vec<Val>>> {
+    let mut store = session.store.try_lock().ok()?;
+    Some(call_instance_func(&mut *store, &session.instance, func_name, params).await)
+}
+
+/// Calls the function of `handle`, or returns `None` when another call holds
+/// its store.
+pub async fn try_call_func_handle(
+    handle: &FuncHandle,
+    params: &[Val],
+) -> Option<anyhow::Result<Vec<Val>>> {
+    let mut store = handle.session.store.try_lock().ok()?;
+    let mut results = default_vals(handle.results.iter().cloned());
+    let called = handle
+        .func
+        .call_async(&mut *store, params, &mut results)
+        .await;
+    Some(called.map(|_| results))
+}
+
+fn default_vals(tys: impl Iterator<Item = ValType>) -> Vec<Val> {
+    tys.map(|ty| match ty {
+        ValType::I32 => Val::I32(0),
+        ValType::I64 => Val::I64(0),
+        ValType::F32 => Val::F32(0),
+        ValType::F64 => Val::F64(0),
+        ValType::V128 => Val::V128(0),
+        ValType::ExternRef => Val::ExternRef(None),
+        ValType::FuncRef => Val::FuncRef(None),
+    })
+    .collect()
+}
+
 fn sval_vec_to_term<'a>(env: Env<'a>, params: Vec<SVal>) -> Term<'a> {
     let mut res: Vec<Term> = Vec::new();
     for param in params {
@@ -72,23 +170,82 @@ fn sval_vec_to_term<'a>(env: Env<'a>, params: Vec<SVal>) -> Term<'a> {
     res.encode(env)
 }
 
+/// Trap raised by an import whose Elixir host function didn't reply in time, or
+/// whose owner process is gone.
+#[derive(Debug)]
+pub struct HostTimeout {
+    pub import_name: String,
+}
+
+impl fmt::Display for HostTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "host function {:?} timed out", self.import_name)
+    }
+}
+
+impl Error for HostTimeout {}
+
+pub fn caller_memory(caller: &mut Caller<'_, ()>) -> Result<Memory, String> {
+    match caller.get_export("memory") {
+        Some(Extern::Memory(memory)) => Ok(memory),
+        _ => Err("memory export not found".into()),
+    }
+}
+
+fn serve_caller(
+    caller: &mut Caller<'_, ()>,
+    request: CallerRequest,
+) -> Result<CallerReply, String> {
+    match request {
+        CallerRequest::ReadMemory(offset, len) => {
+            let memory = caller_memory(caller)?;
+            // Checked before copying anything, since `len` comes from Elixir.
+            match offset.checked_add(len) {
+                Some(end) if end <= memory.data_size(&caller) => Ok(CallerReply::Bytes(
+                    memory.data(&caller)[offset..end].to_vec(),
+                )),
+                _ => Err("out of bounds memory access".into()),
+            }
+        }
+        CallerRequest::WriteMemory(offset, data) => {
+            let memory = caller_memory(caller)?;
+            match memory.write(caller, offset, &data) {
+                Ok(_) => Ok(CallerReply::Written),
+                Err(e) => Err(e.to_string()),
+            }
+        }
+        CallerRequest::GetExport(name) => match caller.get_export(&name) {
+            Some(Extern::Func(_)) => Ok(CallerReply::Export("func")),
+            Some(Extern::Global(_)) => Ok(CallerReply::Export("global")),
+            Some(Extern::Table(_)) => Ok(CallerReply::Export("table")),
+            Some(Extern::Memory(_)) => Ok(CallerReply::Export("memory")),
+            _ => Err(std::format!("export {:?} not found", name)),
+        },
+    }
+}
+
 pub fn imports_valtype_to_extern_recv(
     fn_imports: Vec<(i64, Vec<ValType>, Vec<ValType>)>,
     store: &mut Store<()>,
-    fchs: &HashMap<i64, (crossbeam::Sender<Vec<SVal>>, crossbeam::Receiver<Vec<SVal>>)>,
-    gen_pid: &LocalPid
+    imports: &HashMap<i64, FuncImport>,
+    pending: &Arc<PendingCalls>,
+    gen_pid: &LocalPid,
 ) -> Vec<Extern> {
     let mut _func_imports: Vec<Extern> = Vec::with_capacity(fn_imports.len());
     let mut _store = store;
     for (func_id, func_params, func_results) in fn_imports {
-        match fchs.get(&func_id) {
-            Some(fch) => {
+        match imports.get(&func_id) {
+            Some(import) => {
                 let pid = gen_pid.clone();
-                let recv = fch.1.clone();
-                let fun: Extern = Func::new(
+                let pending = pending.clone();
+                let import_name = import.name.clone();
+                let timeout = import.timeout;
+                let fun: Extern = Func::new_async(
                     &mut _store,
                     FuncType::new(func_params.into_iter(), func_results.into_iter()),
-                    move |_, params, _results| {
+                    move |mut caller, params, _results| {
+                        let pending = pending.clone();
+                        let import_name = import_name.clone();
                         let mut values: Vec<SVal> = Vec::new();
                         for v in params.iter() {
                             match v {
@@ -99,14 +256,45 @@ pub fn imports_valtype_to_extern_recv(
                                 _ => (),
                             }
                         }
+                        let (call_id, mut recv) = pending.register();
                         let mut msg_env = OwnedEnv::new();
                         msg_env.send_and_clear(&pid, |env| {
-                            (atom::call_exfn(), func_id, sval_vec_to_term(env, values)).encode(env)
+                            (
+                                atom::call_exfn(),
+                                func_id,
+                                call_id,
+                                sval_vec_to_term(env, values),
+                            )
+                                .encode(env)
                         });
-                        for (i, result) in recv.recv().unwrap().iter().enumerate() {
-                            _results[i] = result.v.clone();
-                        }
-                        Ok(())
+                        let deadline = timeout.map(|t| tokio::time::Instant::now() + t);
+                        Box::new(async move {
+                            loop {
+                                let msg = match deadline {
+                                    Some(d) => tokio::time::timeout_at(d, recv.recv())
+                                        .await
+                                        .unwrap_or(None),
+                                    None => recv.recv().await,
+                                };
+                                match msg {
+                                    Some(HostMsg::Results(results)) => {
+                                        for (i, result) in results.iter().enumerate() {
+                                            _results[i] = result.v.clone();
+                                        }
+                                        return Ok(());
+                                    }
+                                    Some(HostMsg::Caller(request, reply)) => {
+                                        let _ = reply.send(serve_caller(&mut caller, request));
+                                    }
+                                    None => {
+                                        pending.take(call_id);
+                                        return Err(anyhow::Error::new(HostTimeout {
+                                            import_name,
+                                        }));
+                                    }
+                                }
+                            }
+                        })
                     },
                 )
                 .into();
@@ -119,11 +307,421 @@ pub fn imports_valtype_to_extern_recv(
     _func_imports
 }
 
-fn func_param_tys(tid: i64, func_name: String) -> Result<Vec<ValType>, Box<dyn Error>> {
+/// Resolves `module`'s imports in order: the functions provided by an enabled
+/// native host module are linked to it, the remaining ones take the next
+/// `func_imports`, and each memory, global and table the next one of its kind.
+pub fn resolve_imports(
+    store: &mut Store<()>,
+    module: &Module,
+    host_modules: &[String],
+    ctx: &Arc<HostContext>,
+    func_imports: Vec<Extern>,
+    extern_imports: &ExternImports,
+) -> Result<Vec<Extern>, Box<dyn Error>> {
+    let mut func_imports = func_imports.into_iter();
+    let mut memories = extern_imports.memories.iter();
+    let mut globals = extern_imports.globals.iter();
+    let mut tables = extern_imports.tables.iter();
+    let mut externs: Vec<Extern> = Vec::with_capacity(module.imports().len());
+    for import in module.imports() {
+        if let Some(tid) = extern_imports.links.get(import.module()) {
+            let linked = match session::get_session(*tid) {
+                Some(v) => v,
+                None => return Err("Wasmtime.load(payload) hasn't been called yet".into()),
+            };
+            match linked.instance.get_export(&mut *store, import.name()) {
+                Some(ext) => externs.push(ext),
+                None => {
+                    return Err(std::format!(
+                        "linked module {:?} has no export {:?}",
+                        import.module(),
+                        import.name()
+                    )
+                    .into())
+                }
+            }
+            continue;
+        }
+        let ext: Option<Extern> = match import.ty() {
+            ExternType::Func(_) => {
+                match host::resolve(host_modules, import.module(), import.name())
+                    .and_then(|m| m.func(store, ctx, import.name()))
+                {
+                    Some(fun) => Some(fun.into()),
+                    None => func_imports.next(),
+                }
+            }
+            ExternType::Memory(_) => match memories.next() {
+                Some(MemoryImport::New(ty)) => Some(Memory::new(&mut *store, ty.clone())?.into()),
+                Some(MemoryImport::Export(tid, name)) => {
+                    let exporter = match session::get_session(*tid) {
+                        Some(v) => v,
+                        None => return Err("Wasmtime.load(payload) hasn't been called yet".into()),
+                    };
+                    match exporter.instance.get_memory(&mut *store, name) {
+                        Some(memory) => Some(memory.into()),
+                        None => return Err(std::format!("memory {:?} not found", name).into()),
+                    }
+                }
+                None => None,
+            },
+            ExternType::Global(_) => match globals.next() {
+                Some((ty, val)) => Some(Global::new(&mut *store, ty.clone(), val.clone())?.into()),
+                None => None,
+            },
+            ExternType::Table(_) => match tables.next() {
+                Some(ty) => Some(Table::new(&mut *store, ty.clone(), Val::FuncRef(None))?.into()),
+                None => None,
+            },
+        };
+        match ext {
+            Some(v) => externs.push(v),
+            None => {
+                return Err(std::format!(
+                    "import {:?} {:?} not provided",
+                    import.module(),
+                    import.name()
+                )
+                .into())
+            }
+        }
+    }
+    Ok(externs)
+}
+
+/// Decodes the payload's `memory_imports` (`{min, max}` pages or `{:export, id, name}`
+/// of another session), `global_imports` (`{type, :const | :var, value}`),
+/// `table_imports` (`{min, max}` funcref elements) and `links` (`{name, id}`).
+pub fn extern_imports_from_terms(
+    memory_imports: Vec<Term>,
+    global_imports: Vec<(Atom, Atom, Term)>,
+    table_imports: Vec<(u32, Option<u32>)>,
+    links: Vec<(String, i64)>,
+) -> Result<ExternImports, Box<dyn Error>> {
+    let mut globals: Vec<(GlobalType, Val)> = Vec::with_capacity(global_imports.len());
+    for (ty, mutability, value) in global_imports {
+        let mutability = match mutability {
+            x if x == atom::const_() => Mutability::Const,
+            x if x == atom::var() => Mutability::Var,
+            x => return Err(std::format!("Mutability not supported: {:?}", x).into()),
+        };
+        let val = match args_to_svals(vec![(value, ty)]) {
+            Ok(mut v) if v.len() == 1 => v.remove(0).v,
+            _ => return Err(std::format!("invalid global import value for {:?}", ty).into()),
+        };
+        globals.push((GlobalType::new(val.ty(), mutability), val));
+    }
+    let mut memories: Vec<MemoryImport> = Vec::with_capacity(memory_imports.len());
+    for term in memory_imports {
+        if let Ok((tag, tid, name)) = term.decode::<(Atom, i64, String)>() {
+            if tag == atom::export() {
+                memories.push(MemoryImport::Export(tid, name));
+                continue;
+            }
+        }
+        match term.decode::<(u32, Option<u32>)>() {
+            Ok((min, max)) => memories.push(MemoryImport::New(MemoryType::new(min, max))),
+            Err(_) => return Err(std::format!("invalid memory import: {:?}", term).into()),
+        }
+    }
+    Ok(ExternImports {
+        memories,
+        globals,
+        tables: table_imports
+            .into_iter()
+            .map(|(min, max)| TableType::new(ValType::FuncRef, min, max))
+            .collect(),
+        links: links.into_iter().collect(),
+    })
+}
+
+/// Names of the function imports left to be satisfied by Elixir `func_imports`.
+pub fn func_import_names(
+    module: &Module,
+    host_modules: &[String],
+    extern_imports: &ExternImports,
+) -> Vec<String> {
+    module
+        .imports()
+        .filter(|i| matches!(i.ty(), ExternType::Func(_)))
+        .filter(|i| !extern_imports.links.contains_key(i.module()))
+        .filter(|i| host::resolve(host_modules, i.module(), i.name()).is_none())
+        .map(|i| i.name().to_string())
+        .collect()
+}
+
+/// Seeds an instance's PRNG, differing across instances and loads.
+pub fn random_seed(tid: i64) -> u64 {
+    let nanos = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(v) => v.as_nanos() as u64,
+        Err(_) => 0,
+    };
+    nanos ^ (tid as u64)
+}
+
+/// Returns one of the sessions whose exports are imported, checking they all
+/// share the same store.
+pub fn exporter_session(
+    extern_imports: &ExternImports,
+) -> Result<Option<Arc<Session>>, Box<dyn Error>> {
+    let mut exporter: Option<Arc<Session>> = None;
+    for tid in extern_imports.sessions() {
+        let session = match session::get_session(tid) {
+            Some(v) => v,
+            None => return Err("Wasmtime.load(payload) hasn't been called yet".into()),
+        };
+        match &exporter {
+            Some(e) if !Arc::ptr_eq(&e.store, &session.store) => {
+                return Err("imported exports must come from the same store".into())
+            }
+            Some(_) => (),
+            None => exporter = Some(session),
+        }
+    }
+    Ok(exporter)
+}
+
+/// Compiles a module from `array`, or from `file_name` when it's empty.
+pub fn compile_module(
+    engine: &Engine,
+    file_name: String,
+    array: &[u8],
+) -> Result<Module, Box<dyn Error>> {
+    let module = if array.len() > 0 {
+        Module::new(engine, array)
+    } else {
+        Module::from_file(engine, file_name)
+    };
+    match module {
+        Ok(v) => Ok(v),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub fn valtype_atom(ty: &ValType) -> Atom {
+    match ty {
+        ValType::I32 => atom::i32(),
+        ValType::I64 => atom::i64(),
+        ValType::F32 => atom::f32(),
+        ValType::F64 => atom::f64(),
+        ValType::V128 => atom::v128(),
+        ValType::ExternRef => atom::extern_ref(),
+        ValType::FuncRef => atom::func_ref(),
+    }
+}
+
+/// Encodes the kind of an import or export, and its type: `{params, results}` for
+/// functions, `{type, :const | :var}` for globals, `{min, max}` pages for memories
+/// and `{element_type, min, max}` for tables.
+pub fn extern_type_to_term<'a>(env: Env<'a>, ty: &ExternType) -> (Atom, Term<'a>) {
+    match ty {
+        ExternType::Func(f) => {
+            let params: Vec<Atom> = f.params().map(|v| valtype_atom(&v)).collect();
+            let results: Vec<Atom> = f.results().map(|v| valtype_atom(&v)).collect();
+            (atom::func(), (params, results).encode(env))
+        }
+        ExternType::Global(g) => {
+            let mutability = match g.mutability() {
+                Mutability::Const => atom::const_(),
+                Mutability::Var => atom::var(),
+            };
+            (
+                atom::global(),
+                (valtype_atom(g.content()), mutability).encode(env),
+            )
+        }
+        ExternType::Memory(m) => (atom::memory(), (m.minimum(), m.maximum()).encode(env)),
+        ExternType::Table(t) => (
+            atom::table(),
+            (valtype_atom(&t.element()), t.minimum(), t.maximum()).encode(env),
+        ),
+    }
+}
+
+/// Encodes the full type of an import or export as a map: `params` and `results` for
+/// functions, `type` and `mutability` for globals, `min`, `max`, `shared` and
+/// `memory64` for memories, and `element`, `min` and `max` for tables.
+pub fn extern_type_to_map<'a>(env: Env<'a>, ty: &ExternType) -> Result<Term<'a>, RustlerError> {
+    let (keys, values): (Vec<Atom>, Vec<Term>) = match ty {
+        ExternType::Func(f) => {
+            let params: Vec<Atom> = f.params().map(|v| valtype_atom(&v)).collect();
+            let results: Vec<Atom> = f.results().map(|v| valtype_atom(&v)).collect();
+            (
+                vec![atom::params(), atom::results()],
+                vec![params.encode(env), results.encode(env)],
+            )
+        }
+        ExternType::Global(g) => {
+            let mutability = match g.mutability() {
+                Mutability::Const => atom::const_(),
+                Mutability::Var => atom::var(),
+            };
+            (
+                vec![atom::type_(), atom::mutability()],
+                vec![
+                    valtype_atom(g.content()).encode(env),
+                    mutability.encode(env),
+                ],
+            )
+        }
+        ExternType::Memory(m) => (
+            vec![atom::min(), atom::max(), atom::shared(), atom::memory64()],
+            vec![
+                m.minimum().encode(env),
+                m.maximum().encode(env),
+                m.is_shared().encode(env),
+                m.is_64().encode(env),
+            ],
+        ),
+        ExternType::Table(t) => (
+            vec![atom::element(), atom::min(), atom::max()],
+            vec![
+                valtype_atom(&t.element()).encode(env),
+                t.minimum().encode(env),
+                t.maximum().encode(env),
+            ],
+        ),
+    };
+    Term::map_from_arrays(env, &keys, &values)
+}
+
+/// Parses WAT text into its binary encoding. Errors carry the 1-based line and column
+/// they were found at.
+pub fn wat_to_wasm(text: &str) -> Result<Vec<u8>, (usize, usize, String)> {
+    fn located(text: &str, e: wast::Error) -> (usize, usize, String) {
+        let (line, column) = e.span().linecol_in(text);
+        (line + 1, column + 1, e.message())
+    }
+    let buf = wast::parser::ParseBuffer::new(text).map_err(|e| located(text, e))?;
+    let mut wat = wast::parser::parse::<wast::Wat>(&buf).map_err(|e| located(text, e))?;
+    wat.encode().map_err(|e| located(text, e))
+}
+
+/// Payloads of the custom sections named `name`, in order. `bin` may be WAT text.
+pub fn custom_sections(bin: &[u8], name: &str) -> Result<Vec<Vec<u8>>, Box<dyn Error>> {
+    let bytes = wat::parse_bytes(bin)?;
+    let mut sections: Vec<Vec<u8>> = Vec::new();
+    for payload in wasmparser::Parser::new(0).parse_all(&bytes) {
+        if let wasmparser::Payload::CustomSection(reader) = payload? {
+            if reader.name() == name {
+                sections.push(reader.data().to_vec());
+            }
+        }
+    }
+    Ok(sections)
+}
+
+/// An import as `(module, name, type)`.
+pub type ParsedImport = (String, String, ExternType);
+
+/// Reads a module's imports from its import section without compiling it. `bin`
+/// is read from `file_name` when empty.
+pub fn parse_imports(file_name: String, bin: &[u8]) -> Result<Vec<ParsedImport>, Box<dyn Error>> {
+    let bin = if bin.is_empty() {
+        std::fs::read(file_name)?
+    } else {
+        bin.to_vec()
+    };
+    let bytes = wat::parse_bytes(&bin)?;
+    let mut types: Vec<FuncType> = Vec::new();
+    let mut imports: Vec<ParsedImport> = Vec::new();
+    for payload in wasmparser::Parser::new(0).parse_all(&bytes) {
+        match payload? {
+            wasmparser::Payload::TypeSection(reader) => {
+                for ty in reader {
+                    let wasmparser::Type::Func(f) = ty?;
+                    types.push(FuncType::new(
+                        f.params().iter().map(parsed_valtype),
+                        f.results().iter().map(parsed_valtype),
+                    ));
+                }
+            }
+            wasmparser::Payload::ImportSection(reader) => {
+                for import in reader {
+                    let import = import?;
+                    let ty: ExternType = match import.ty {
+                        wasmparser::TypeRef::Func(index) => match types.get(index as usize) {
+                            Some(f) => f.clone().into(),
+                            None => return Err(std::format!("unknown type {}", index).into()),
+                        },
+                        wasmparser::TypeRef::Table(t) => {
+                            TableType::new(parsed_valtype(&t.element_type), t.initial, t.maximum)
+                                .into()
+                        }
+                        wasmparser::TypeRef::Memory(m) => parsed_memory_type(&m).into(),
+                        wasmparser::TypeRef::Global(g) => {
+                            let mutability = if g.mutable {
+                                Mutability::Var
+                            } else {
+                                Mutability::Const
+                            };
+                            GlobalType::new(parsed_valtype(&g.content_type), mutability).into()
+                        }
+                        wasmparser::TypeRef::Tag(_) => {
+                            return Err("tag imports aren't supported".into())
+                        }
+                    };
+                    imports.push((import.module.to_string(), import.name.to_string(), ty));
+                }
+            }
+            _ => (),
+        }
+    }
+    Ok(imports)
+}
+
+fn parsed_valtype(ty: &wasmparser::ValType) -> ValType {
+    match ty {
+        wasmparser::ValType::I32 => ValType::I32,
+        wasmparser::ValType::I64 => ValType::I64,
+        wasmparser::ValType::F32 => ValType::F32,
+        wasmparser::ValType::F64 => ValType::F64,
+        wasmparser::ValType::V128 => ValType::V128,
+        wasmparser::ValType::FuncRef => ValType::FuncRef,
+        wasmparser::ValType::ExternRef => ValType::ExternRef,
+    }
+}
+
+fn parsed_memory_type(m: &wasmparser::MemoryType) -> MemoryType {
+    if m.memory64 {
+        MemoryType::new64(m.initial, m.maximum)
+    } else if m.shared {
+        MemoryType::shared(m.initial as u32, m.maximum.unwrap_or(m.initial) as u32)
+    } else {
+        MemoryType::new(m.initial as u32, m.maximum.map(|v| v as u32))
+    }
+}
+
+/// Function names from the `name` custom section, by function index.
+pub fn function_names(bin: &[u8]) -> Result<HashMap<u32, String>, Box<dyn Error>> {
+    let mut names: HashMap<u32, String> = HashMap::new();
+    for data in custom_sections(bin, "name")? {
+        for name in wasmparser::NameSectionReader::new(&data, 0)? {
+            if let wasmparser::Name::Function(map) = name? {
+                for naming in map {
+                    let naming = naming?;
+                    names.insert(naming.index, naming.name.to_string());
+                }
+            }
+        }
+    }
+    Ok(names)
+}
+
+pub fn check_host_modules(host_modules: &[String]) -> Result<(), Box<dyn Error>> {
+    for name in host_modules {
+        if host::lookup(name).is_none() {
+            return Err(std::format!("host module {:?} not found", name).into());
+        }
+    }
+    Ok(())
+}
+
+pub fn func_param_tys(tid: i64, func_name: String) -> Result<Vec<ValType>, Box<dyn Error>> {
     let mut tys: Vec<ValType> = Vec::new();
     if let Some(session) = SESSIONS.read().unwrap().get(&tid) {
         match session.exports.get(&func_name) {
-            Some(v) => {
+            Some((_, v)) => {
                 for val in v.iter() {
                     tys.push(val.ty.clone());
                 }
@@ -138,57 +736,294 @@ fn func_param_tys(tid: i64, func_name: String) -> Result<Vec<ValType>, Box<dyn E
     }
 }
 
-pub fn imports_with_exports_tys(
-    tid: i64,
-    func_name: String,
-    func_imports: &Vec<(i64, Vec<Atom>, Vec<Atom>)>,
-) -> Result<(Vec<(i64, Vec<ValType>, Vec<ValType>)>, Vec<ValType>), Box<dyn Error>> {
-    let fn_imports = match imports_term_to_valtype(func_imports) {
-        Ok(v) => v,
-        Err(e) => {
-            return Err(e.to_string().into());
-        }
-    };
-    let tys = match func_param_tys(tid, func_name.clone()) {
-        Ok(v) => v,
-        Err(e) => {
-            return Err(e.to_string().into());
+/// Arguments that don't match the parameters of the function they're passed to.
+pub enum BadArguments<'a> {
+    /// Not exactly one argument per parameter.
+    Arity { expected: usize, got: usize },
+    /// The argument at `index` doesn't fit the type of its parameter.
+    Type {
+        index: usize,
+        expected: ValType,
+        got: Term<'a>,
+    },
+}
+
+impl<'a> Encoder for BadArguments<'a> {
+    fn encode<'b>(&self, env: Env<'b>) -> Term<'b> {
+        match self {
+            BadArguments::Arity { expected, got } => (atom::bad_arity(), expected, got).encode(env),
+            BadArguments::Type {
+                index,
+                expected,
+                got,
+            } => (
+                atom::bad_argument(),
+                index,
+                valtype_atom(expected),
+                got.in_env(env),
+            )
+                .encode(env),
         }
-    };
-    Ok((fn_imports, tys))
+    }
 }
 
-pub fn args_ty_to_svals(
-    args: &Vec<Term>,
-    tys: &Vec<ValType>,
-) -> Result<Vec<SVal>, RustlerError> {
-    let mut values: Vec<SVal> = Vec::new();
-    for (param, ty) in args.iter().zip(tys) {
-        match ty {
-            ValType::I32 => values.push(SVal {
-                v: Val::I32(param.decode()?),
-            }),
-            ValType::I64 => values.push(SVal {
-                v: Val::I64(param.decode()?),
-            }),
-            ValType::F32 => {
-                let arg: f32 = param.decode()?;
-                values.push(SVal {
-                    v: Val::F32(arg.to_bits()),
+/// Converts one argument per parameter. Integers have to fit the bit width of
+/// their parameter, read as signed or unsigned, and floats have to be given as
+/// floats.
+pub fn args_ty_to_svals<'a>(
+    args: &[Term<'a>],
+    tys: &[ValType],
+) -> Result<Vec<SVal>, BadArguments<'a>> {
+    if args.len() != tys.len() {
+        return Err(BadArguments::Arity {
+            expected: tys.len(),
+            got: args.len(),
+        });
+    }
+    let mut values: Vec<SVal> = Vec::with_capacity(tys.len());
+    for (index, (arg, ty)) in args.iter().zip(tys).enumerate() {
+        match arg_to_val(*arg, ty) {
+            Some(v) => values.push(SVal { v }),
+            None => {
+                return Err(BadArguments::Type {
+                    index,
+                    expected: ty.clone(),
+                    got: *arg,
                 })
             }
-            ValType::F64 => {
-                let arg: f64 = param.decode()?;
-                values.push(SVal {
-                    v: Val::F64(arg.to_bits()),
-                })
+        }
+    }
+    Ok(values)
+}
+
+fn arg_to_val(arg: Term, ty: &ValType) -> Option<Val> {
+    match ty {
+        ValType::I32 => arg
+            .decode::<i32>()
+            .or_else(|_| arg.decode::<u32>().map(|v| v as i32))
+            .ok()
+            .map(Val::I32),
+        ValType::I64 => arg
+            .decode::<i64>()
+            .or_else(|_| arg.decode::<u64>().map(|v| v as i64))
+            .ok()
+            .map(Val::I64),
+        ValType::F32 => arg
+            .decode::<f64>()
+            .ok()
+            .map(|v| Val::F32((v as f32).to_bits())),
+        ValType::F64 => arg.decode::<f64>().ok().map(|v| Val::F64(v.to_bits())),
+        _ => None,
+    }
+}
+
+/// An argument of a call passing binaries: either a scalar, or bytes the guest
+/// receives as a pointer and a length.
+pub enum BinArg {
+    Val(Val),
+    Bytes(Vec<u8>),
+}
+
+/// Like `args_ty_to_svals`, except that each binary argument takes two `i32`
+/// parameters, its pointer and its length.
+pub fn args_ty_to_bin_args<'a>(
+    args: &[Term<'a>],
+    tys: &[ValType],
+) -> Result<Vec<BinArg>, BadArguments<'a>> {
+    let width: usize = args
+        .iter()
+        .map(|arg| if arg.is_binary() { 2 } else { 1 })
+        .sum();
+    if width != tys.len() {
+        return Err(BadArguments::Arity {
+            expected: tys.len(),
+            got: width,
+        });
+    }
+    let mut values: Vec<BinArg> = Vec::with_capacity(args.len());
+    let mut tys = tys.iter();
+    for (index, arg) in args.iter().enumerate() {
+        let value = if arg.is_binary() {
+            match (tys.next(), tys.next(), arg.decode::<Binary>()) {
+                (Some(ValType::I32), Some(ValType::I32), Ok(bin)) => {
+                    Ok(BinArg::Bytes(bin.as_slice().to_vec()))
+                }
+                (Some(ValType::I32), Some(ValType::I32), Err(_)) => Err(ValType::I32),
+                (Some(ValType::I32), Some(ty), _) | (Some(ty), _, _) => Err(ty.clone()),
+                _ => Err(ValType::I32),
+            }
+        } else {
+            match tys.next() {
+                Some(ty) => arg_to_val(*arg, ty)
+                    .map(BinArg::Val)
+                    .ok_or_else(|| ty.clone()),
+                None => Err(ValType::I32),
             }
-            _ => (),
         };
+        match value {
+            Ok(v) => values.push(v),
+            Err(expected) => {
+                return Err(BadArguments::Type {
+                    index,
+                    expected,
+                    got: *arg,
+                })
+            }
+        }
     }
     Ok(values)
 }
 
+/// Names of the guest exports a call passing binaries goes through, decoded from
+/// `{alloc, dealloc, memory}`.
+#[derive(NifTuple)]
+pub struct GuestAbi {
+    pub alloc: String,
+    pub dealloc: String,
+    pub memory: String,
+}
+
+/// Results of a call passing binaries: either its values as they are, or the
+/// bytes its `(ptr, len)` result points to.
+pub enum BinResults {
+    Vals(Vec<Val>),
+    Bytes(Vec<u8>),
+}
+
+/// Calls `func_name` after copying each binary argument into a buffer the guest
+/// allocates with `abi.alloc`, and frees the buffers with `abi.dealloc` once it
+/// returns. With `binary_result`, the function returns a pointer and a length,
+/// either as two `i32`s or packed in an `i64` with the pointer in its high
+/// bits, and the bytes they point to are read back and freed as well.
+pub async fn call_session_func_with_binaries(
+    session: &Session,
+    func_name: &str,
+    args: Vec<BinArg>,
+    abi: &GuestAbi,
+    binary_result: bool,
+) -> anyhow::Result<BinResults> {
+    let mut store = session.store.lock().await;
+    let store = &mut *store;
+    let instance = &session.instance;
+    let memory = match instance.get_memory(&mut *store, &abi.memory) {
+        Some(v) => v,
+        None => {
+            return Err(anyhow::Error::msg(std::format!(
+                "memory {:?} not found",
+                abi.memory
+            )))
+        }
+    };
+
+    let mut buffers: Vec<(i32, i32)> = Vec::new();
+    let mut params: Vec<Val> = Vec::new();
+    let mut copied: anyhow::Result<()> = Ok(());
+    for arg in args {
+        match arg {
+            BinArg::Val(v) => params.push(v),
+            BinArg::Bytes(bytes) => {
+                let len = match i32::try_from(bytes.len()) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        copied = Err(e.into());
+                        break;
+                    }
+                };
+                let ptr = match guest_alloc(store, instance, abi, len).await {
+                    Ok(v) => v,
+                    Err(e) => {
+                        copied = Err(e);
+                        break;
+                    }
+                };
+                buffers.push((ptr, len));
+                if let Err(e) = memory.write(&mut *store, ptr as u32 as usize, &bytes) {
+                    copied = Err(e.into());
+                    break;
+                }
+                params.push(Val::I32(ptr));
+                params.push(Val::I32(len));
+            }
+        }
+    }
+    let called = match copied {
+        Ok(_) => call_instance_func(store, instance, func_name, &params).await,
+        Err(e) => Err(e),
+    };
+    let mut freed: anyhow::Result<()> = Ok(());
+    for (ptr, len) in buffers {
+        if let Err(e) = guest_dealloc(store, instance, abi, ptr, len).await {
+            freed = Err(e);
+        }
+    }
+    let results = called?;
+    freed?;
+    if !binary_result {
+        return Ok(BinResults::Vals(results));
+    }
+
+    let (ptr, len) = match results.as_slice() {
+        [Val::I32(ptr), Val::I32(len)] => (*ptr, *len),
+        [Val::I64(packed)] => ((*packed >> 32) as i32, *packed as i32),
+        _ => {
+            return Err(anyhow::Error::msg(std::format!(
+                "function {:?} doesn't return a pointer and a length",
+                func_name
+            )))
+        }
+    };
+    let (start, end) = (
+        ptr as u32 as usize,
+        ptr as u32 as usize + len as u32 as usize,
+    );
+    if end > memory.data_size(&*store) {
+        // The buffer is still handed back to the guest, which may have allocated it
+        // before getting its bounds wrong. Failing to free it doesn't hide the
+        // error below.
+        let _ = guest_dealloc(store, instance, abi, ptr, len).await;
+        return Err(anyhow::Error::msg(std::format!(
+            "function {:?} returned bytes out of the bounds of memory",
+            func_name
+        )));
+    }
+    let bytes = memory.data(&*store)[start..end].to_vec();
+    guest_dealloc(store, instance, abi, ptr, len).await?;
+    Ok(BinResults::Bytes(bytes))
+}
+
+async fn guest_alloc(
+    store: &mut Store<()>,
+    instance: &Instance,
+    abi: &GuestAbi,
+    len: i32,
+) -> anyhow::Result<i32> {
+    let results = call_instance_func(store, instance, &abi.alloc, &[Val::I32(len)]).await?;
+    match results.as_slice() {
+        [Val::I32(ptr)] => Ok(*ptr),
+        _ => Err(anyhow::Error::msg(std::format!(
+            "function {:?} doesn't return an i32 pointer",
+            abi.alloc
+        ))),
+    }
+}
+
+async fn guest_dealloc(
+    store: &mut Store<()>,
+    instance: &Instance,
+    abi: &GuestAbi,
+    ptr: i32,
+    len: i32,
+) -> anyhow::Result<()> {
+    call_instance_func(
+        store,
+        instance,
+        &abi.dealloc,
+        &[Val::I32(ptr), Val::I32(len)],
+    )
+    .await?;
+    Ok(())
+}
+
 pub fn args_to_svals(args: Vec<(Term, Atom)>) -> Result<Vec<SVal>, RustlerError> {
     let mut values: Vec<SVal> = Vec::new();
     for (arg, ty) in args.iter() {
@@ -217,20 +1052,50 @@ pub fn args_to_svals(args: Vec<(Term, Atom)>) -> Result<Vec<SVal>, RustlerError>
     Ok(values)
 }
 
+const ASYNC_STACK_HEADROOM: usize = 1 << 20;
+
 pub fn gen_config(config: &config::Config) -> Result<Config, Box<dyn Error>> {
     let mut cfg = Config::new();
     cfg.debug_info(config.debug_info);
+    cfg.async_support(true);
+    // Lets guests yield to other calls, see `runtime::yield_on_epoch`.
+    cfg.epoch_interruption(true);
     cfg.max_wasm_stack(config.max_wasm_stack);
-    let strategy = match &config.strategy {
-        x if x == "cranelift" => Strategy::Cranelift,
-        _ => Strategy::Auto,
-    };
-    let cranelift_opt_level = match &config.cranelift_opt_level {
-        x if x == "speed" => OptLevel::Speed,
-        x if x == "speed_and_size" => OptLevel::SpeedAndSize,
-        _ => OptLevel::None,
-    };
-    cfg.cranelift_opt_level(cranelift_opt_level);
-    cfg.strategy(strategy);
+    // Fibers need room for the host frames on top of the wasm stack.
+    cfg.async_stack_size(config.max_wasm_stack + ASYNC_STACK_HEADROOM);
+    cfg.cranelift_opt_level(config.cranelift_opt_level.clone());
+    cfg.strategy(config.strategy);
+    cfg.wasm_simd(config.simd);
+    cfg.wasm_bulk_memory(config.bulk_memory);
+    cfg.wasm_reference_types(config.reference_types);
+    cfg.wasm_multi_value(config.multi_value);
+    cfg.wasm_multi_memory(config.multi_memory);
+    cfg.wasm_memory64(config.memory64);
+    cfg.wasm_threads(config.threads);
+    if let Some(size) = config.static_memory_maximum_size {
+        cfg.static_memory_maximum_size(size);
+    }
+    if let Some(size) = config.static_memory_guard_size {
+        cfg.static_memory_guard_size(size);
+    }
+    if let Some(size) = config.dynamic_memory_guard_size {
+        cfg.dynamic_memory_guard_size(size);
+    }
+    if let Some(size) = config.dynamic_memory_reserved_for_growth {
+        cfg.dynamic_memory_reserved_for_growth(size);
+    }
+    cfg.parallel_compilation(config.parallel_compilation);
+    cfg.cranelift_nan_canonicalization(config.cranelift_nan_canonicalization);
+    cfg.generate_address_map(config.generate_address_map);
+    cfg.native_unwind_info(config.native_unwind_info);
+    for (name, value) in &config.cranelift_flags {
+        // SAFETY: a flag can change the code Cranelift generates in ways Wasmtime
+        // relies on not to change, so this is only as sound as the flags given.
+        // `Wasmtime.Config` documents that they must come from trusted input only.
+        // Unknown flags and values are reported by `Engine::new`.
+        unsafe {
+            cfg.cranelift_flag_set(name, value);
+        }
+    }
     Ok(cfg)
 }
@@ -0,0 +1,142 @@
+use crate::atom;
+use crate::aux;
+use crate::host::{HostContext, HostModule};
+use rustler::{Encoder, OwnedEnv};
+use std::ops::Range;
+use std::sync::Arc;
+use wasmtime::{Caller, Func, Memory, Store};
+
+/// Host functions most guests need, imported from the `wasmtime_ex` module:
+///
+/// - `log(level: i32, ptr: i32, len: i32)` forwards a message to Elixir's `Logger`,
+///   `level` being 0 (debug), 1 (info), 2 (warning) or 3 (error).
+/// - `monotonic_now() -> i64` nanoseconds elapsed since the instance was loaded.
+/// - `wall_now() -> i64` nanoseconds since the Unix epoch.
+/// - `random_bytes(ptr: i32, len: i32)` fills guest memory from the instance's seeded PRNG.
+///
+/// With a deterministic config, both clocks are virtual and advance by a fixed tick on
+/// every read, and the PRNG is seeded with the config's `deterministic_seed`.
+pub struct Builtins;
+
+pub const NAME: &str = "wasmtime_ex";
+
+impl HostModule for Builtins {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    fn fields(&self) -> &'static [&'static str] {
+        &["log", "monotonic_now", "wall_now", "random_bytes"]
+    }
+
+    fn func(&self, store: &mut Store<()>, ctx: &Arc<HostContext>, field: &str) -> Option<Func> {
+        let ctx = ctx.clone();
+        match field {
+            "log" => Some(Func::wrap(
+                store,
+                move |caller: Caller<'_, ()>, level: i32, ptr: i32, len: i32| {
+                    log(&ctx, caller, level, ptr, len)
+                },
+            )),
+            "monotonic_now" => Some(Func::wrap(store, move || -> i64 {
+                ctx.monotonic_now() as i64
+            })),
+            "wall_now" => Some(Func::wrap(store, move || -> i64 { ctx.wall_now() as i64 })),
+            "random_bytes" => Some(Func::wrap(
+                store,
+                move |caller: Caller<'_, ()>, ptr: i32, len: i32| {
+                    random_bytes(&ctx, caller, ptr, len)
+                },
+            )),
+            _ => None,
+        }
+    }
+}
+
+fn log(
+    ctx: &HostContext,
+    mut caller: Caller<'_, ()>,
+    level: i32,
+    ptr: i32,
+    len: i32,
+) -> anyhow::Result<()> {
+    let msg = read_guest(&mut caller, ptr, len)?;
+    let level = match level {
+        0 => atom::debug(),
+        1 => atom::info(),
+        2 => atom::warning(),
+        _ => atom::error(),
+    };
+    OwnedEnv::new().send_and_clear(&ctx.gen_pid, |env| {
+        (
+            atom::wasm_log(),
+            level,
+            String::from_utf8_lossy(&msg).into_owned(),
+        )
+            .encode(env)
+    });
+    Ok(())
+}
+
+fn random_bytes(
+    ctx: &HostContext,
+    mut caller: Caller<'_, ()>,
+    ptr: i32,
+    len: i32,
+) -> anyhow::Result<()> {
+    let memory = aux::caller_memory(&mut caller).map_err(anyhow::Error::msg)?;
+    let range = guest_range(&memory, &caller, ptr, len)?;
+    ctx.rng
+        .lock()
+        .unwrap()
+        .fill(&mut memory.data_mut(&mut caller)[range]);
+    Ok(())
+}
+
+fn read_guest(caller: &mut Caller<'_, ()>, ptr: i32, len: i32) -> anyhow::Result<Vec<u8>> {
+    let memory = aux::caller_memory(caller).map_err(anyhow::Error::msg)?;
+    let range = guest_range(&memory, caller, ptr, len)?;
+    Ok(memory.data(&caller)[range].to_vec())
+}
+
+/// The bytes `ptr` and `len` point to, checked against the memory's size before
+/// anything is copied.
+fn guest_range(
+    memory: &Memory,
+    caller: &Caller<'_, ()>,
+    ptr: i32,
+    len: i32,
+) -> anyhow::Result<Range<usize>> {
+    let start = ptr as u32 as usize;
+    match start.checked_add(len as u32 as usize) {
+        Some(end) if end <= memory.data_size(caller) => Ok(start..end),
+        _ => Err(anyhow::Error::msg("out of bounds memory access")),
+    }
+}
+
+/// SplitMix64, which is small, fast and good enough for guests' non-cryptographic
+/// randomness.
+pub struct SplitMix64 {
+    state: u64,
+}
+
+impl SplitMix64 {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    pub fn fill(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+}
@@ -1,9 +1,232 @@
-use serde::{Deserialize, Serialize};
+use rustler::{Atom, Decoder, Encoder, Env, NifStruct, Term};
+use wasmtime::{OptLevel, Strategy};
 
-#[derive(Serialize, Deserialize, Debug)]
+use crate::atom;
+
+/// `Wasmtime.Config` as decoded from Elixir, before its fields are checked.
+#[derive(NifStruct)]
+#[module = "Wasmtime.Config"]
+#[rustler(decode)]
+struct ConfigTerms<'a> {
+    debug_info: Term<'a>,
+    max_wasm_stack: Term<'a>,
+    strategy: Term<'a>,
+    cranelift_opt_level: Term<'a>,
+    simd: Term<'a>,
+    bulk_memory: Term<'a>,
+    reference_types: Term<'a>,
+    multi_value: Term<'a>,
+    multi_memory: Term<'a>,
+    memory64: Term<'a>,
+    threads: Term<'a>,
+    relaxed_simd: Term<'a>,
+    tail_call: Term<'a>,
+    static_memory_maximum_size: Term<'a>,
+    static_memory_guard_size: Term<'a>,
+    dynamic_memory_guard_size: Term<'a>,
+    dynamic_memory_reserved_for_growth: Term<'a>,
+    parallel_compilation: Term<'a>,
+    cranelift_nan_canonicalization: Term<'a>,
+    generate_address_map: Term<'a>,
+    native_unwind_info: Term<'a>,
+    cranelift_flags: Term<'a>,
+    deterministic: Term<'a>,
+    deterministic_seed: Term<'a>,
+}
+
+#[derive(Debug, Clone)]
 pub struct Config {
     pub debug_info: bool,
     pub max_wasm_stack: usize,
-    pub strategy: String,
-    pub cranelift_opt_level: String,
+    pub strategy: Strategy,
+    pub cranelift_opt_level: OptLevel,
+    pub simd: bool,
+    pub bulk_memory: bool,
+    pub reference_types: bool,
+    pub multi_value: bool,
+    pub multi_memory: bool,
+    pub memory64: bool,
+    pub threads: bool,
+    pub static_memory_maximum_size: Option<u64>,
+    pub static_memory_guard_size: Option<u64>,
+    pub dynamic_memory_guard_size: Option<u64>,
+    pub dynamic_memory_reserved_for_growth: Option<u64>,
+    pub parallel_compilation: bool,
+    pub cranelift_nan_canonicalization: bool,
+    pub generate_address_map: bool,
+    pub native_unwind_info: bool,
+    pub cranelift_flags: Vec<(String, String)>,
+    pub deterministic: bool,
+    pub deterministic_seed: u64,
+}
+
+/// A `Wasmtime.Config` field holding a value that isn't supported.
+pub struct InvalidConfig<'a> {
+    pub field: &'static str,
+    pub value: Term<'a>,
+}
+
+impl<'a> Encoder for InvalidConfig<'a> {
+    fn encode<'b>(&self, env: Env<'b>) -> Term<'b> {
+        let field = match Atom::from_str(env, self.field) {
+            Ok(v) => v.encode(env),
+            Err(_) => self.field.encode(env),
+        };
+        (atom::invalid_config(), field, self.value.in_env(env)).encode(env)
+    }
+}
+
+impl Config {
+    /// Decodes and checks every field of a `Wasmtime.Config` struct.
+    pub fn decode(term: Term) -> Result<Config, InvalidConfig> {
+        let terms: ConfigTerms = match term.decode() {
+            Ok(v) => v,
+            Err(_) => {
+                return Err(InvalidConfig {
+                    field: "config",
+                    value: term,
+                })
+            }
+        };
+        let max_wasm_stack: usize = field("max_wasm_stack", terms.max_wasm_stack)?;
+        if max_wasm_stack == 0 {
+            return Err(invalid("max_wasm_stack", terms.max_wasm_stack));
+        }
+        let strategy = match atom_name(terms.strategy).as_deref() {
+            Some("auto") => Strategy::Auto,
+            Some("cranelift") => Strategy::Cranelift,
+            _ => return Err(invalid("strategy", terms.strategy)),
+        };
+        let cranelift_opt_level = match atom_name(terms.cranelift_opt_level).as_deref() {
+            Some("none") => OptLevel::None,
+            Some("speed") => OptLevel::Speed,
+            Some("speed_and_size") => OptLevel::SpeedAndSize,
+            _ => return Err(invalid("cranelift_opt_level", terms.cranelift_opt_level)),
+        };
+        // Tail calls and relaxed SIMD aren't implemented by this version of Wasmtime.
+        if field::<bool>("tail_call", terms.tail_call)? {
+            return Err(invalid("tail_call", terms.tail_call));
+        }
+        if field::<bool>("relaxed_simd", terms.relaxed_simd)? {
+            return Err(invalid("relaxed_simd", terms.relaxed_simd));
+        }
+        let mut config = Config {
+            debug_info: field("debug_info", terms.debug_info)?,
+            max_wasm_stack,
+            strategy,
+            cranelift_opt_level,
+            simd: field("simd", terms.simd)?,
+            bulk_memory: field("bulk_memory", terms.bulk_memory)?,
+            reference_types: field("reference_types", terms.reference_types)?,
+            multi_value: field("multi_value", terms.multi_value)?,
+            multi_memory: field("multi_memory", terms.multi_memory)?,
+            memory64: field("memory64", terms.memory64)?,
+            threads: field("threads", terms.threads)?,
+            static_memory_maximum_size: field(
+                "static_memory_maximum_size",
+                terms.static_memory_maximum_size,
+            )?,
+            static_memory_guard_size: field(
+                "static_memory_guard_size",
+                terms.static_memory_guard_size,
+            )?,
+            dynamic_memory_guard_size: field(
+                "dynamic_memory_guard_size",
+                terms.dynamic_memory_guard_size,
+            )?,
+            dynamic_memory_reserved_for_growth: field(
+                "dynamic_memory_reserved_for_growth",
+                terms.dynamic_memory_reserved_for_growth,
+            )?,
+            parallel_compilation: field("parallel_compilation", terms.parallel_compilation)?,
+            cranelift_nan_canonicalization: field(
+                "cranelift_nan_canonicalization",
+                terms.cranelift_nan_canonicalization,
+            )?,
+            generate_address_map: field("generate_address_map", terms.generate_address_map)?,
+            native_unwind_info: field("native_unwind_info", terms.native_unwind_info)?,
+            cranelift_flags: field("cranelift_flags", terms.cranelift_flags)?,
+            deterministic: field("deterministic", terms.deterministic)?,
+            deterministic_seed: field("deterministic_seed", terms.deterministic_seed)?,
+        };
+        // The deterministic preset rules out the proposals whose results may differ
+        // across hosts, and canonicalizes NaNs.
+        if config.deterministic {
+            if config.threads {
+                return Err(invalid("threads", terms.threads));
+            }
+            config.cranelift_nan_canonicalization = true;
+        }
+        Ok(config)
+    }
+
+    /// Whether an engine built from `self` behaves as one built from `other`.
+    /// The deterministic preset's clock and seed are per instance, so they're
+    /// left out, while the NaN canonicalization it implies is compared.
+    pub fn same_engine(&self, other: &Config) -> bool {
+        let Config {
+            debug_info,
+            max_wasm_stack,
+            strategy,
+            cranelift_opt_level,
+            simd,
+            bulk_memory,
+            reference_types,
+            multi_value,
+            multi_memory,
+            memory64,
+            threads,
+            static_memory_maximum_size,
+            static_memory_guard_size,
+            dynamic_memory_guard_size,
+            dynamic_memory_reserved_for_growth,
+            parallel_compilation,
+            cranelift_nan_canonicalization,
+            generate_address_map,
+            native_unwind_info,
+            cranelift_flags,
+            deterministic: _,
+            deterministic_seed: _,
+        } = self;
+        let same_strategy = matches!(
+            (strategy, &other.strategy),
+            (Strategy::Auto, Strategy::Auto) | (Strategy::Cranelift, Strategy::Cranelift)
+        );
+        same_strategy
+            && *debug_info == other.debug_info
+            && *max_wasm_stack == other.max_wasm_stack
+            && *cranelift_opt_level == other.cranelift_opt_level
+            && *simd == other.simd
+            && *bulk_memory == other.bulk_memory
+            && *reference_types == other.reference_types
+            && *multi_value == other.multi_value
+            && *multi_memory == other.multi_memory
+            && *memory64 == other.memory64
+            && *threads == other.threads
+            && *static_memory_maximum_size == other.static_memory_maximum_size
+            && *static_memory_guard_size == other.static_memory_guard_size
+            && *dynamic_memory_guard_size == other.dynamic_memory_guard_size
+            && *dynamic_memory_reserved_for_growth == other.dynamic_memory_reserved_for_growth
+            && *parallel_compilation == other.parallel_compilation
+            && *cranelift_nan_canonicalization == other.cranelift_nan_canonicalization
+            && *generate_address_map == other.generate_address_map
+            && *native_unwind_info == other.native_unwind_info
+            && *cranelift_flags == other.cranelift_flags
+    }
+}
+
+fn invalid<'a>(field: &'static str, value: Term<'a>) -> InvalidConfig<'a> {
+    InvalidConfig { field, value }
+}
+
+fn field<'a, T: Decoder<'a>>(name: &'static str, value: Term<'a>) -> Result<T, InvalidConfig<'a>> {
+    value.decode().map_err(|_| invalid(name, value))
+}
+
+fn atom_name(term: Term) -> Option<String> {
+    if term.is_atom() {
+        term.atom_to_string().ok()
+    } else {
+        None
+    }
 }
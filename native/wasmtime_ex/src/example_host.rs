@@ -0,0 +1,32 @@
+use crate::host::{HostContext, HostModule};
+use std::sync::Arc;
+use wasmtime::{Func, Store};
+
+/// A minimal native host module, built with the `example_host` cargo feature, to
+/// copy when writing one. Guests import it as the `example` module:
+///
+/// - `add(a: i64, b: i64) -> i64` wrapping addition.
+/// - `clamp(v: i64, lo: i64, hi: i64) -> i64` `v` bounded by `lo` and `hi`.
+pub struct ExampleHost;
+
+impl HostModule for ExampleHost {
+    fn name(&self) -> &'static str {
+        "example"
+    }
+
+    fn fields(&self) -> &'static [&'static str] {
+        &["add", "clamp"]
+    }
+
+    fn func(&self, store: &mut Store<()>, _ctx: &Arc<HostContext>, field: &str) -> Option<Func> {
+        match field {
+            "add" => Some(Func::wrap(store, |a: i64, b: i64| -> i64 {
+                a.wrapping_add(b)
+            })),
+            "clamp" => Some(Func::wrap(store, |v: i64, lo: i64, hi: i64| -> i64 {
+                v.max(lo).min(hi)
+            })),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,115 @@
+use crate::builtins::{self, SplitMix64};
+use lazy_static::lazy_static;
+use rustler::LocalPid;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use wasmtime::{Func, Store};
+
+lazy_static! {
+    static ref HOST_MODULES: HashMap<&'static str, Box<dyn HostModule>> = registry();
+}
+
+/// Nanoseconds a deterministic clock advances by on every read.
+const VIRTUAL_CLOCK_TICK: u64 = 1_000;
+
+/// State of the session a native host module is being linked into.
+pub struct HostContext {
+    pub gen_pid: LocalPid,
+    pub started: Instant,
+    pub rng: Mutex<SplitMix64>,
+    /// Virtual time in nanoseconds, used instead of the host clocks when deterministic.
+    virtual_clock: Option<AtomicU64>,
+}
+
+impl HostContext {
+    pub fn new(gen_pid: LocalPid, seed: u64) -> Self {
+        Self {
+            gen_pid,
+            started: Instant::now(),
+            rng: Mutex::new(SplitMix64::new(seed)),
+            virtual_clock: None,
+        }
+    }
+
+    /// A context whose clocks are virtual and whose randomness is seeded with `seed`,
+    /// so guests see the same values on every run.
+    pub fn deterministic(gen_pid: LocalPid, seed: u64) -> Self {
+        Self {
+            virtual_clock: Some(AtomicU64::new(0)),
+            ..Self::new(gen_pid, seed)
+        }
+    }
+
+    /// Nanoseconds elapsed since the instance was loaded.
+    pub fn monotonic_now(&self) -> u64 {
+        match &self.virtual_clock {
+            Some(clock) => self.tick(clock),
+            None => self.started.elapsed().as_nanos() as u64,
+        }
+    }
+
+    /// Nanoseconds since the Unix epoch. Deterministic contexts start at the epoch.
+    pub fn wall_now(&self) -> u64 {
+        match &self.virtual_clock {
+            Some(clock) => self.tick(clock),
+            None => match SystemTime::now().duration_since(UNIX_EPOCH) {
+                Ok(v) => v.as_nanos() as u64,
+                Err(_) => 0,
+            },
+        }
+    }
+
+    fn tick(&self, clock: &AtomicU64) -> u64 {
+        clock.fetch_add(VIRTUAL_CLOCK_TICK, Ordering::SeqCst) + VIRTUAL_CLOCK_TICK
+    }
+}
+
+/// A module of host functions implemented in Rust. They run synchronously on the
+/// wasm thread, without a round-trip to Elixir.
+///
+/// Implementations are compiled into the NIF behind a cargo feature and pushed into
+/// `registry` under that feature, as `example_host::ExampleHost` is. One living in
+/// its own crate is added to `[dependencies]` with `optional = true`, and the feature
+/// lists it as `my_host_module = ["dep:my_host_module"]`. Elixir enables them per
+/// instance by name through the payload's `host_modules`. Modules reading
+/// time or randomness should go through the `HostContext`, which virtualises them
+/// in deterministic mode.
+pub trait HostModule: Send + Sync {
+    /// Module name guests import from, as in `(import "name" "field" ...)`.
+    fn name(&self) -> &'static str;
+
+    /// Fields this module provides.
+    fn fields(&self) -> &'static [&'static str];
+
+    /// Creates the host function for `field` in `store`.
+    fn func(&self, store: &mut Store<()>, ctx: &Arc<HostContext>, field: &str) -> Option<Func>;
+}
+
+fn registry() -> HashMap<&'static str, Box<dyn HostModule>> {
+    #[allow(unused_mut)]
+    let mut modules: Vec<Box<dyn HostModule>> = vec![Box::new(builtins::Builtins)];
+    #[cfg(feature = "example_host")]
+    modules.push(Box::new(crate::example_host::ExampleHost));
+    modules.into_iter().map(|m| (m.name(), m)).collect()
+}
+
+pub fn lookup(name: &str) -> Option<&'static dyn HostModule> {
+    HOST_MODULES.get(name).map(|m| m.as_ref())
+}
+
+/// Returns the enabled host module providing `module`.`field`, if any.
+pub fn resolve(
+    host_modules: &[String],
+    module: &str,
+    field: &str,
+) -> Option<&'static dyn HostModule> {
+    if !host_modules.iter().any(|m| m == module) {
+        return None;
+    }
+    match lookup(module) {
+        Some(m) if m.fields().contains(&field) => Some(m),
+        _ => None,
+    }
+}
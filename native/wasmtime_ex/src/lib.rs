@@ -1,16 +1,27 @@
 pub mod atom;
 pub mod aux;
+pub mod builtins;
 pub mod config;
+#[cfg(feature = "example_host")]
+pub mod example_host;
+pub mod host;
+pub mod runtime;
 pub mod session;
 
 use rustler::Error as RustlerError;
-use rustler::{Atom, Encoder, Env, LocalPid, OwnedEnv, Term};
+use rustler::{
+    Atom, Binary, Encoder, Env, LocalPid, NifTuple, OwnedBinary, OwnedEnv, ResourceArc, Term,
+};
 
-use crate::session::{SVal, SValType, Session, SESSIONS};
-use crossbeam::channel::unbounded;
+use crate::host::HostContext;
+use crate::session::{
+    CallerReply, CallerRequest, ExternImports, FuncHandle, FuncImport, HostMsg, PendingCalls,
+    SValType, Session, SharedStore, SESSIONS,
+};
 use std::collections::HashMap;
 use std::error::Error;
-use std::thread;
+use std::sync::Arc;
+use std::time::Duration;
 use wasmtime::Val;
 use wasmtime::*;
 
@@ -20,109 +31,90 @@ rustler::init!(
         load_from,
         call_func,
         call_func_xt,
+        call_many,
+        call_with_binaries,
+        func_handle,
+        call_handle,
         get_func,
         exfn_reply,
-        exports
-    ]
+        exports,
+        imports,
+        module_imports,
+        describe,
+        module_describe,
+        validate,
+        wat2wasm,
+        wasm2wat,
+        custom_sections,
+        function_names,
+        configure_pool,
+        pool_stats,
+        unload,
+        caller_read_memory,
+        caller_write_memory,
+        caller_get_export
+    ],
+    load = load
 );
 
+// `resource!` expands to an `impl` inside this function.
+#[allow(non_local_definitions)]
+fn load(env: Env, _: Term) -> bool {
+    rustler::resource!(FuncHandle, env);
+    true
+}
+
 #[rustler::nif(schedule = "DirtyCpu")]
 fn call_func_xt<'a>(
     env: Env<'a>,
     tid: i64,
     func_name: String,
     params: Vec<Term<'a>>,
+    unsigned: bool,
 ) -> Result<Term<'a>, RustlerError> {
-    if let Some(session) = SESSIONS.read().unwrap().get(&tid) {
-        let mut store = Store::new(session.module.engine(), ());
-        let instance = match Instance::new(&mut store, &session.module, &[]) {
-            Ok(v) => v,
-            Err(e) => return Ok((atom::error(), e.to_string()).encode(env)),
-        };
-
-        match instance.get_func(&mut store, &func_name) {
-            Some(f) => {
-                let mut args: Vec<Val> = Vec::new();
-                for (i, v) in f.ty(&store).params().enumerate() {
-                    match v {
-                        ValType::I32 => args.push(Val::I32(params.get(i).unwrap().decode()?)),
-                        ValType::I64 => args.push(Val::I64(params.get(i).unwrap().decode()?)),
-                        _ => (),
-                    }
-                }
-                let mut res: Vec<Val> = Vec::new();
-                let func_ty = f.ty(&mut store);
-                for result in func_ty.results() {
-                  match result {
-                      ValType::I32 => {res.push(Val::I32(0));},
-                      ValType::I64 => {res.push(Val::I64(0));},
-                      ValType::F32 => {res.push(Val::F32(0));},
-                      ValType::F64 => {res.push(Val::F64(0));},
-                      _ => ()
-                  }
-                }
-                match f.call(&mut store, &args, &mut res) {
-                    Ok(v) => v,
-                    Err(e) => {
-                        return Ok((atom::error(), e.to_string()).encode(env));
-                    }
-                };
-
-                let mut results: Vec<Term> = Vec::new();
-                for (i, v) in f.ty(store).results().enumerate() {
-                    match v {
-                        ValType::I32 => {
-                            results.push((res.get(i).unwrap().unwrap_i32()).encode(env))
-                        }
-                        ValType::I64 => {
-                            results.push((res.get(i).unwrap().unwrap_i64()).encode(env))
-                        }
-                        ValType::F32 => {
-                            results.push((res.get(i).unwrap().unwrap_f32()).encode(env));
-                        }
-                        ValType::F64 => {
-                            results.push((res.get(i).unwrap().unwrap_f64()).encode(env))
-                        }
-                        _ => (),
-                    };
-                }
-
-                return Ok((atom::ok(), results).encode(env));
-            }
-            None => {
-                return Ok((
-                    atom::error(),
-                    std::format!("function {:?} not found", func_name),
-                )
-                    .encode(env))
-            }
-        };
-    } else {
-        Ok((
-            atom::error(),
-            "Wasmtime.load(payload) hasn't been called yet",
-        )
-            .encode(env))
+    let session = match session::get_session(tid) {
+        Some(v) => v,
+        None => {
+            return Ok((
+                atom::error(),
+                "Wasmtime.load(payload) hasn't been called yet",
+            )
+                .encode(env))
+        }
+    };
+    let tys = match aux::func_param_tys(tid, func_name.clone()) {
+        Ok(v) => v,
+        Err(e) => return Ok((atom::error(), e.to_string()).encode(env)),
+    };
+    let args: Vec<Val> = match aux::args_ty_to_svals(&params, &tys) {
+        Ok(v) => v.into_iter().map(|v| v.v).collect(),
+        Err(e) => return Ok((atom::error(), e).encode(env)),
+    };
+    // Waiting on the store would hold a dirty scheduler for as long as the
+    // running call, which may itself be waiting on one to get its import replies.
+    match runtime::RUNTIME.block_on(aux::try_call_session_func(&session, &func_name, &args)) {
+        Some(called) => Ok(called_to_term(env, called, unsigned)),
+        None => Ok((atom::error(), atom::busy()).encode(env)),
     }
 }
 
-#[rustler::nif(schedule = "DirtyCpu")]
+#[rustler::nif]
 fn exfn_reply<'a>(
     env: Env<'a>,
     tid: i64,
-    func_id: i64,
+    call_id: u64,
     results: Vec<(Term, Atom)>,
 ) -> Result<Term<'a>, RustlerError> {
     let results = aux::args_to_svals(results)?;
 
     if let Some(session) = SESSIONS.read().unwrap().get(&tid) {
-        if let Some(fch) = session.fchs.get(&func_id) {
-            match fch.0.send(results) {
+        if let Some(sender) = session.pending.take(call_id) {
+            match sender.send(HostMsg::Results(results)) {
                 Ok(_) => Ok((atom::ok()).encode(env)),
                 Err(_) => Ok((atom::error(), "exfn_reply failed to send").encode(env)),
             }
         } else {
-            Ok((atom::error(), "exfn_reply failed to get func_id").encode(env))
+            Ok((atom::error(), "exfn_reply call is no longer pending").encode(env))
         }
     } else {
         Ok((
@@ -133,6 +125,16 @@ fn exfn_reply<'a>(
     }
 }
 
+/// Memory, global and table imports and links of a load payload, as built by
+/// `extern_imports_to_term` in Elixir.
+#[derive(NifTuple)]
+struct ExternImportTerms<'a> {
+    memories: Vec<Term<'a>>,
+    globals: Vec<(Atom, Atom, Term<'a>)>,
+    tables: Vec<(u32, Option<u32>)>,
+    links: Vec<(String, i64)>,
+}
+
 #[rustler::nif]
 fn load_from<'a>(
     env: Env<'a>,
@@ -142,11 +144,14 @@ fn load_from<'a>(
     file_name: String,
     bin: Vec<u8>,
     func_imports: Vec<(i64, Vec<Atom>, Vec<Atom>)>,
-    config_val: String,
+    import_timeouts: Vec<(i64, Option<u64>)>,
+    host_modules: Vec<String>,
+    extern_imports: ExternImportTerms<'a>,
+    config: Term<'a>,
 ) -> Result<Term<'a>, RustlerError> {
-    let config: config::Config = match serde_json::from_str(&config_val) {
+    let config = match config::Config::decode(config) {
         Ok(v) => v,
-        Err(e) => return Ok((atom::error(), e.to_string()).encode(env)),
+        Err(e) => return Ok((atom::error(), e).encode(env)),
     };
 
     let func_imports = match aux::imports_term_to_valtype(&func_imports) {
@@ -154,80 +159,137 @@ fn load_from<'a>(
         Err(e) => return Ok((atom::error(), e.to_string()).encode(env)),
     };
 
-    thread::spawn(move || {
-        fn run(
+    let extern_imports = match aux::extern_imports_from_terms(
+        extern_imports.memories,
+        extern_imports.globals,
+        extern_imports.tables,
+        extern_imports.links,
+    ) {
+        Ok(v) => v,
+        Err(e) => return Ok((atom::error(), e.to_string()).encode(env)),
+    };
+
+    let submitted = runtime::submit(async move {
+        async fn run(
             tid: i64,
             gen_pid: &LocalPid,
             from_encoded: &String,
             array: &[u8],
             file_name: String,
             func_imports: Vec<(i64, Vec<ValType>, Vec<ValType>)>,
+            import_timeouts: Vec<(i64, Option<u64>)>,
+            host_modules: Vec<String>,
+            extern_imports: ExternImports,
             config: &config::Config,
         ) -> Result<(), Box<dyn Error>> {
-            let config = match aux::gen_config(config) {
-                Ok(v) => v,
-                Err(e) => return Err(e.into()),
-            };
-            let engine = match Engine::new(&config) {
-                Ok(v) => v,
-                Err(e) => return Err(e.into()),
+            aux::check_host_modules(&host_modules)?;
+            let exporter = aux::exporter_session(&extern_imports)?;
+            // An instance sharing another's store is compiled by its engine, which has
+            // to be configured the same way.
+            if let Some(exporter) = &exporter {
+                if !exporter.config.same_engine(config) {
+                    return Err("config differs from the one of the instances imported from".into());
+                }
+            }
+            let (engine, epoch) = match &exporter {
+                Some(v) => (v.module.engine().clone(), v.epoch.clone()),
+                None => {
+                    let config = match aux::gen_config(config) {
+                        Ok(v) => v,
+                        Err(e) => return Err(e.into()),
+                    };
+                    let engine = match Engine::new(&config) {
+                        Ok(v) => v,
+                        Err(e) => return Err(e.into()),
+                    };
+                    let epoch = runtime::start_epoch(&engine);
+                    (engine, epoch)
+                }
             };
-            let store = Store::new(&engine, ());
-            let mut msg_env = OwnedEnv::new();
+            let module =
+                tokio::task::block_in_place(|| aux::compile_module(&engine, file_name, array))?;
 
-            let module = if array.len() > 0 {
-                match Module::new(store.engine(), array) {
-                    Ok(v) => v,
-                    Err(e) => return Err(e.into()),
+            let timeouts: HashMap<i64, Option<u64>> = import_timeouts.into_iter().collect();
+            let import_names = aux::func_import_names(&module, &host_modules, &extern_imports);
+            let mut funcs: HashMap<i64, FuncImport> = HashMap::with_capacity(func_imports.len());
+            for (func_id, name) in func_imports.iter().map(|x| x.0).zip(import_names) {
+                let timeout = timeouts
+                    .get(&func_id)
+                    .cloned()
+                    .flatten()
+                    .map(Duration::from_millis);
+                funcs.insert(func_id, FuncImport { name, timeout });
+            }
+
+            let shared_store: SharedStore = match &exporter {
+                Some(v) => v.store.clone(),
+                None => {
+                    let mut store = Store::new(&engine, ());
+                    runtime::yield_on_epoch(&mut store);
+                    Arc::new(tokio::sync::Mutex::new(store))
                 }
+            };
+            let mut store = shared_store.lock().await;
+            let pending = Arc::new(PendingCalls::default());
+            let func_imports = aux::imports_valtype_to_extern_recv(
+                func_imports,
+                &mut store,
+                &funcs,
+                &pending,
+                gen_pid,
+            );
+            let ctx = if config.deterministic {
+                HostContext::deterministic(*gen_pid, config.deterministic_seed)
             } else {
-                match Module::from_file(store.engine(), file_name) {
-                    Ok(v) => v,
-                    Err(e) => return Err(e.into()),
-                }
+                HostContext::new(*gen_pid, aux::random_seed(tid))
             };
-
-            let mut store = Store::new(module.engine(), ());
-            let func_ids: Vec<i64> = func_imports.iter().map(|x| x.0).collect();
-            let func_imports = aux::imports_valtype_to_extern(func_imports, &mut store);
+            let ctx = Arc::new(ctx);
+            let externs = aux::resolve_imports(
+                &mut store,
+                &module,
+                &host_modules,
+                &ctx,
+                func_imports,
+                &extern_imports,
+            )?;
 
             let instance =
-                match Instance::new(&mut store, &module, &*func_imports.into_boxed_slice()) {
+                match Instance::new_async(&mut *store, &module, &*externs.into_boxed_slice()).await
+                {
                     Ok(v) => v,
                     Err(e) => return Err(e.into()),
                 };
 
-            let mut _exports: HashMap<String, Vec<SValType>> = HashMap::new();
-            let exported_functions = instance
-                .exports(&mut store)
-                .map(|e| (e.name().to_owned(), e.into_func()))
-                .filter_map(|(n, f)| f.map(|f| (n, f)))
-                .collect::<Vec<_>>();
-            let exp_funcs = exported_functions
-                .into_iter()
-                .map(|(n, f)| (n, f.ty(&mut store)))
-                .collect::<Vec<(String, FuncType)>>();
-            for (name, v) in exp_funcs {
-                let mut params: Vec<SValType> = Vec::new();
-                for param in v.params() {
-                    params.push(SValType { ty: param.clone() });
-                };
-                _exports.insert(name, params);
-            }
-
-            let mut fchs: HashMap<
-                i64,
-                (crossbeam::Sender<Vec<SVal>>, crossbeam::Receiver<Vec<SVal>>),
-            > = HashMap::with_capacity(func_ids.len());
-            for func_id in func_ids {
-                let fch: (crossbeam::Sender<Vec<SVal>>, crossbeam::Receiver<Vec<SVal>>) =
-                    unbounded();
-                fchs.insert(func_id, fch);
+            // Exported functions are resolved while the store is at hand, so handles
+            // to them don't need it.
+            let mut _exports: HashMap<String, (Func, Vec<SValType>)> = HashMap::new();
+            for export in module.exports() {
+                if let ExternType::Func(v) = export.ty() {
+                    let func = match instance.get_func(&mut *store, export.name()) {
+                        Some(f) => f,
+                        None => continue,
+                    };
+                    let mut params: Vec<SValType> = Vec::new();
+                    for param in v.params() {
+                        params.push(SValType { ty: param.clone() });
+                    }
+                    _exports.insert(export.name().to_string(), (func, params));
+                }
             }
+            drop(store);
 
-            let session = Box::new(Session::new(module, fchs, _exports));
+            let session = Arc::new(Session::new(
+                module,
+                config.clone(),
+                epoch,
+                shared_store,
+                instance,
+                _exports,
+                pending,
+            ));
             SESSIONS.write().unwrap().insert(tid, session);
 
+            let mut msg_env = OwnedEnv::new();
             msg_env.send_and_clear(gen_pid, |env| {
                 (atom::gen_reply(), from_encoded, atom::ok()).encode(env)
             });
@@ -241,8 +303,13 @@ fn load_from<'a>(
             &bin,
             file_name,
             func_imports,
+            import_timeouts,
+            host_modules,
+            extern_imports,
             &config,
-        ) {
+        )
+        .await
+        {
             Ok(_) => (),
             Err(e) => {
                 let mut msg_env = OwnedEnv::new();
@@ -257,7 +324,10 @@ fn load_from<'a>(
             }
         };
     });
-    Ok((atom::ok()).encode(env))
+    match submitted {
+        Ok(_) => Ok((atom::ok()).encode(env)),
+        Err(_) => Ok((atom::error(), atom::overloaded()).encode(env)),
+    }
 }
 
 #[rustler::nif(schedule = "DirtyCpu")]
@@ -267,156 +337,244 @@ fn call_func<'a>(
     gen_pid: LocalPid,
     from_encoded: String,
     func_name: String,
-    params: Vec<Term>,
-    func_imports: Vec<(i64, Vec<Atom>, Vec<Atom>)>,
+    params: Vec<Term<'a>>,
+    unsigned: bool,
+) -> Result<Term<'a>, RustlerError> {
+    let tys = match aux::func_param_tys(tid, func_name.clone()) {
+        Ok(v) => v,
+        Err(e) => {
+            env.send(
+                &gen_pid,
+                (
+                    atom::gen_reply(),
+                    from_encoded,
+                    (atom::error(), e.to_string()),
+                )
+                    .encode(env),
+            );
+            return Ok((atom::ok()).encode(env));
+        }
+    };
+    let svals = match aux::args_ty_to_svals(&params, &tys) {
+        Ok(v) => v,
+        Err(e) => return Ok((atom::error(), e).encode(env)),
+    };
+
+    let submitted = runtime::submit(async move {
+        let params: Vec<Val> = svals.into_iter().map(|v| v.v).collect();
+        let called = match session::get_session(tid) {
+            Some(session) => aux::call_session_func(&session, &func_name, &params).await,
+            None => Err(anyhow::Error::msg(
+                "Wasmtime.load(payload) hasn't been called yet",
+            )),
+        };
+        let mut msg_env = OwnedEnv::new();
+        msg_env.send_and_clear(&gen_pid, |env| {
+            (
+                atom::gen_reply(),
+                from_encoded,
+                called_to_term(env, called, unsigned),
+            )
+                .encode(env)
+        });
+    });
+    match submitted {
+        Ok(_) => Ok((atom::ok()).encode(env)),
+        Err(_) => Ok((atom::error(), atom::overloaded()).encode(env)),
+    }
+}
+
+#[rustler::nif(schedule = "DirtyCpu")]
+fn call_many<'a>(
+    env: Env<'a>,
+    tid: i64,
+    gen_pid: LocalPid,
+    from_encoded: String,
+    calls: Vec<(String, Vec<Term<'a>>)>,
+    continue_on_error: bool,
+    unsigned: bool,
 ) -> Result<Term<'a>, RustlerError> {
-    let (func_imports, tys) =
-        match aux::imports_with_exports_tys(tid, func_name.clone(), &func_imports) {
+    // Every call is checked before any of them runs.
+    let mut batch: Vec<(String, Vec<Val>)> = Vec::with_capacity(calls.len());
+    for (index, (func_name, params)) in calls.into_iter().enumerate() {
+        let tys = match aux::func_param_tys(tid, func_name.clone()) {
             Ok(v) => v,
-            Err(e) => {
-                env.send(
-                    &gen_pid,
-                    (
-                        atom::gen_reply(),
-                        from_encoded,
-                        (atom::error(), e.to_string()),
-                    )
-                        .encode(env),
-                );
-                return Ok((atom::ok()).encode(env));
-            }
+            Err(e) => return Ok((atom::error(), (index, e.to_string())).encode(env)),
+        };
+        match aux::args_ty_to_svals(&params, &tys) {
+            Ok(v) => batch.push((func_name, v.into_iter().map(|v| v.v).collect())),
+            Err(e) => return Ok((atom::error(), (index, e)).encode(env)),
         };
-    let svals = aux::args_ty_to_svals(&params, &tys)?;
+    }
 
-    thread::spawn(move || {
-        fn run(
-            tid: i64,
-            gen_pid: &LocalPid,
-            from_encoded: &String,
-            func_name: String,
-            func_imports: Vec<(i64, Vec<ValType>, Vec<ValType>)>,
-            svals: Vec<SVal>,
-        ) -> Result<(), Box<dyn Error>> {
-            if let Some(session) = SESSIONS.read().unwrap().get(&tid) {
-                let mut store = Store::new(session.module.engine(), ());
-                let func_imports = aux::imports_valtype_to_extern_recv(
-                    func_imports,
-                    &mut store,
-                    &session.fchs,
-                    &gen_pid.clone(),
-                );
-
-                let instance = match Instance::new(
-                    &mut store,
-                    &session.module,
-                    &*func_imports.into_boxed_slice(),
-                ) {
-                    Ok(v) => v,
-                    Err(e) => return Err(e.into()),
-                };
+    let submitted = runtime::submit(async move {
+        let called = match session::get_session(tid) {
+            Some(session) => Ok(aux::call_session_funcs(&session, &batch, continue_on_error).await),
+            None => Err("Wasmtime.load(payload) hasn't been called yet"),
+        };
+        let mut msg_env = OwnedEnv::new();
+        msg_env.send_and_clear(&gen_pid, |env| {
+            let reply = match called {
+                Ok(results) => {
+                    let results: Vec<Term> = results
+                        .into_iter()
+                        .map(|called| called_to_term(env, called, unsigned))
+                        .collect();
+                    (atom::ok(), results).encode(env)
+                }
+                Err(e) => (atom::error(), e).encode(env),
+            };
+            (atom::gen_reply(), from_encoded, reply).encode(env)
+        });
+    });
+    match submitted {
+        Ok(_) => Ok((atom::ok()).encode(env)),
+        Err(_) => Ok((atom::error(), atom::overloaded()).encode(env)),
+    }
+}
 
-                let func = instance.get_func(&mut store, &func_name).unwrap();
-                OwnedEnv::new().send_and_clear(&gen_pid, |env| {
-                    let mut params: Vec<Val> = Vec::new();
-                    for val in svals {
-                        params.push(val.v);
-                    }
-                    let mut res: Vec<Val> = Vec::new();
-                    let func_ty = func.ty(&mut store);
-                    for result in func_ty.results() {
-                      match result {
-                          ValType::I32 => {res.push(Val::I32(0));},
-                          ValType::I64 => {res.push(Val::I64(0));},
-                          ValType::F32 => {res.push(Val::F32(0));},
-                          ValType::F64 => {res.push(Val::F64(0));},
-                          _ => ()
-                      }
-                    }
-                    match func.call(&mut store, &params, &mut res) {
-                        Ok(v) => v,
-                        Err(e) => {
-                            return (atom::gen_reply(), from_encoded, e.to_string()).encode(env)
-                        }
-                    };
-                    let mut results: Vec<Term> = Vec::new();
-                    for (i, v) in func.ty(store).results().enumerate() {
-                        match v {
-                            ValType::I32 => {
-                                results.push((res.get(i).unwrap().unwrap_i32()).encode(env));
-                            }
-                            ValType::I64 => {
-                                results.push((res.get(i).unwrap().unwrap_i64()).encode(env));
-                            }
-                            ValType::F32 => {
-                                results.push((res.get(i).unwrap().unwrap_f32()).encode(env));
-                            }
-                            ValType::F64 => {
-                                results.push((res.get(i).unwrap().unwrap_f64()).encode(env));
-                            }
-                            _ => (),
-                        };
-                    }
+/// How the results of a call passing binaries are read, as `{binary_result, unsigned}`.
+#[derive(NifTuple)]
+struct BinResultsOpts {
+    binary_result: bool,
+    unsigned: bool,
+}
 
-                    (atom::gen_reply(), from_encoded, (atom::ok(), results)).encode(env)
-                });
-                Ok(())
-            } else {
-                Ok(())
-            }
-        }
+/// Called by the instance's GenServer, which the reply is sent to.
+#[rustler::nif(schedule = "DirtyCpu")]
+fn call_with_binaries<'a>(
+    env: Env<'a>,
+    tid: i64,
+    from_encoded: String,
+    func_name: String,
+    params: Vec<Term<'a>>,
+    abi: aux::GuestAbi,
+    opts: BinResultsOpts,
+) -> Result<Term<'a>, RustlerError> {
+    let gen_pid = env.pid();
+    let BinResultsOpts {
+        binary_result,
+        unsigned,
+    } = opts;
+    let tys = match aux::func_param_tys(tid, func_name.clone()) {
+        Ok(v) => v,
+        Err(e) => return Ok((atom::error(), e.to_string()).encode(env)),
+    };
+    let args = match aux::args_ty_to_bin_args(&params, &tys) {
+        Ok(v) => v,
+        Err(e) => return Ok((atom::error(), e).encode(env)),
+    };
 
-        match run(
-            tid,
-            &gen_pid,
-            &from_encoded,
-            func_name.to_string(),
-            func_imports,
-            svals,
-        ) {
-            Ok(_) => (),
-            Err(e) => {
-                let mut msg_env = OwnedEnv::new();
-                msg_env.send_and_clear(&gen_pid, |env| {
-                    (
-                        atom::gen_reply(),
-                        from_encoded,
-                        (atom::error(), e.to_string()),
-                    )
-                        .encode(env)
-                });
+    let submitted = runtime::submit(async move {
+        let called = match session::get_session(tid) {
+            Some(session) => {
+                aux::call_session_func_with_binaries(
+                    &session,
+                    &func_name,
+                    args,
+                    &abi,
+                    binary_result,
+                )
+                .await
             }
+            None => Err(anyhow::Error::msg(
+                "Wasmtime.load(payload) hasn't been called yet",
+            )),
         };
+        let mut msg_env = OwnedEnv::new();
+        msg_env.send_and_clear(&gen_pid, |env| {
+            let reply = match called {
+                Ok(aux::BinResults::Vals(res)) => called_to_term(env, Ok(res), unsigned),
+                Ok(aux::BinResults::Bytes(bytes)) => match OwnedBinary::new(bytes.len()) {
+                    Some(mut bin) => {
+                        bin.as_mut_slice().copy_from_slice(&bytes);
+                        (atom::ok(), bin.release(env)).encode(env)
+                    }
+                    None => (atom::error(), "failed to allocate binary").encode(env),
+                },
+                Err(e) => called_to_term(env, Err(e), unsigned),
+            };
+            (atom::gen_reply(), from_encoded, reply).encode(env)
+        });
     });
-    Ok((atom::ok()).encode(env))
+    match submitted {
+        Ok(_) => Ok((atom::ok()).encode(env)),
+        Err(_) => Ok((atom::error(), atom::overloaded()).encode(env)),
+    }
+}
+
+fn called_to_term<'a>(env: Env<'a>, called: anyhow::Result<Vec<Val>>, unsigned: bool) -> Term<'a> {
+    match called {
+        Ok(res) => (atom::ok(), aux::vals_to_terms(env, &res, unsigned)).encode(env),
+        Err(e) => match e.downcast_ref::<aux::HostTimeout>() {
+            Some(t) => (atom::error(), (atom::host_timeout(), t.import_name.clone())).encode(env),
+            None => (atom::error(), e.to_string()).encode(env),
+        },
+    }
 }
 
 #[rustler::nif]
-fn get_func<'a>(
+fn func_handle<'a>(env: Env<'a>, tid: i64, func_name: String) -> Result<Term<'a>, RustlerError> {
+    let session = match session::get_session(tid) {
+        Some(v) => v,
+        None => {
+            return Ok((
+                atom::error(),
+                "Wasmtime.load(payload) hasn't been called yet",
+            )
+                .encode(env))
+        }
+    };
+    let func = session.exports.get(&func_name).map(|(func, _)| *func);
+    let (func, ty) = match (func, session.module.get_export(&func_name)) {
+        (Some(func), Some(ExternType::Func(ty))) => (func, ty),
+        _ => {
+            return Ok((
+                atom::error(),
+                std::format!("function {:?} not found", func_name),
+            )
+                .encode(env))
+        }
+    };
+    let handle = ResourceArc::new(FuncHandle {
+        tid,
+        params: ty.params().collect(),
+        results: ty.results().collect(),
+        session,
+        func,
+    });
+    Ok((atom::ok(), handle).encode(env))
+}
+
+#[rustler::nif(schedule = "DirtyCpu")]
+fn call_handle<'a>(
     env: Env<'a>,
-    tid: i64,
-    func_name: String,
-    func_imports: Vec<(i64, Vec<Atom>, Vec<Atom>)>,
+    handle: ResourceArc<FuncHandle>,
+    params: Vec<Term<'a>>,
+    unsigned: bool,
 ) -> Result<Term<'a>, RustlerError> {
+    if !SESSIONS.read().unwrap().contains_key(&handle.tid) {
+        return Ok((atom::error(), "the instance has been unloaded").encode(env));
+    }
+    let args: Vec<Val> = match aux::args_ty_to_svals(&params, &handle.params) {
+        Ok(v) => v.into_iter().map(|v| v.v).collect(),
+        Err(e) => return Ok((atom::error(), e).encode(env)),
+    };
+    // Like `call_func_xt`, fails rather than wait on the store.
+    match runtime::RUNTIME.block_on(aux::try_call_func_handle(&handle, &args)) {
+        Some(called) => Ok(called_to_term(env, called, unsigned)),
+        None => Ok((atom::error(), atom::busy()).encode(env)),
+    }
+}
+
+#[rustler::nif]
+fn get_func<'a>(env: Env<'a>, tid: i64, func_name: String) -> Result<Term<'a>, RustlerError> {
     if let Some(session) = SESSIONS.read().unwrap().get(&tid) {
-        let mut store = Store::new(session.module.engine(), ());
-        let func_imports = match aux::imports_term_to_valtype(&func_imports) {
-            Ok(v) => v,
-            Err(e) => return Ok((atom::error(), e.to_string()).encode(env)),
-        };
-        let func_imports = aux::imports_valtype_to_extern(func_imports, &mut store);
-        let instance = match Instance::new(
-            &mut store,
-            &session.module,
-            &*func_imports.into_boxed_slice(),
-        ) {
-            Ok(v) => v,
-            Err(e) => return Ok((atom::error(), e.to_string()).encode(env)),
-        };
-        match instance.get_func(&mut store, &func_name) {
-            Some(f) => {
+        match session.module.get_export(&func_name) {
+            Some(ExternType::Func(f)) => {
                 let mut params: Vec<Term> = Vec::new();
                 let mut results: Vec<Term> = Vec::new();
-                for v in f.ty(&store).clone().params() {
+                for v in f.params() {
                     match v {
                         ValType::I32 => params.push((atom::i32()).encode(env)),
                         ValType::I64 => params.push((atom::i64()).encode(env)),
@@ -427,7 +585,7 @@ fn get_func<'a>(
                         ValType::FuncRef => params.push((atom::func_ref()).encode(env)),
                     };
                 }
-                for v in f.ty(&store).clone().results() {
+                for v in f.results() {
                     match v {
                         ValType::I32 => results.push((atom::i32()).encode(env)),
                         ValType::I64 => results.push((atom::i64()).encode(env)),
@@ -444,7 +602,7 @@ fn get_func<'a>(
                 }
                 return Ok((atom::ok(), (params, results)).encode(env));
             }
-            None => {
+            _ => {
                 return Ok((
                     atom::error(),
                     std::format!("function {:?} not found", func_name),
@@ -462,43 +620,15 @@ fn get_func<'a>(
 }
 
 #[rustler::nif]
-fn exports<'a>(
-    env: Env<'a>,
-    tid: i64,
-    func_imports: Vec<(i64, Vec<Atom>, Vec<Atom>)>,
-) -> Result<Term<'a>, RustlerError> {
+fn exports<'a>(env: Env<'a>, tid: i64) -> Result<Term<'a>, RustlerError> {
     if let Some(session) = SESSIONS.read().unwrap().get(&tid) {
-        let mut store = Store::new(session.module.engine(), ());
-        let func_imports = match aux::imports_term_to_valtype(&func_imports) {
-            Ok(v) => v,
-            Err(e) => return Ok((atom::error(), e.to_string()).encode(env)),
-        };
-        let func_imports = aux::imports_valtype_to_extern(func_imports, &mut store);
-        let instance = match Instance::new(
-            &mut store,
-            &session.module,
-            &*func_imports.into_boxed_slice(),
-        ) {
-            Ok(v) => v,
-            Err(e) => return Ok((atom::error(), e.to_string()).encode(env)),
-        };
-
         let mut _exports: Vec<(&str, Term)> = Vec::new();
-        for v in instance.exports(&mut store) {
-            if let Some(_) = v.clone().into_func() {
-                _exports.push((v.name(), atom::func().encode(env)));
-                continue;
-            }
-            if let Some(_) = v.clone().into_global() {
-                _exports.push((v.name(), atom::global().encode(env)));
-                continue;
-            }
-            if let Some(_) = v.clone().into_table() {
-                _exports.push((v.name(), atom::table().encode(env)));
-                continue;
-            }
-            if let Some(_) = v.clone().into_memory() {
-                _exports.push((v.name(), atom::memory().encode(env)));
+        for v in session.module.exports() {
+            match v.ty() {
+                ExternType::Func(_) => _exports.push((v.name(), atom::func().encode(env))),
+                ExternType::Global(_) => _exports.push((v.name(), atom::global().encode(env))),
+                ExternType::Table(_) => _exports.push((v.name(), atom::table().encode(env))),
+                ExternType::Memory(_) => _exports.push((v.name(), atom::memory().encode(env))),
             }
         }
         Ok((atom::ok(), _exports).encode(env))
@@ -510,3 +640,308 @@ fn exports<'a>(
             .encode(env))
     }
 }
+
+#[rustler::nif]
+fn imports<'a>(env: Env<'a>, tid: i64) -> Result<Term<'a>, RustlerError> {
+    if let Some(session) = SESSIONS.read().unwrap().get(&tid) {
+        Ok((atom::ok(), module_imports_to_term(env, &session.module)).encode(env))
+    } else {
+        Ok((
+            atom::error(),
+            "Wasmtime.load(payload) hasn't been called yet",
+        )
+            .encode(env))
+    }
+}
+
+/// Lists the imports of a module without compiling it.
+#[rustler::nif(schedule = "DirtyCpu")]
+fn module_imports<'a>(
+    env: Env<'a>,
+    file_name: String,
+    bin: Vec<u8>,
+) -> Result<Term<'a>, RustlerError> {
+    match aux::parse_imports(file_name, &bin) {
+        Ok(v) => {
+            let parsed = v
+                .iter()
+                .map(|(module, name, ty)| (&module[..], &name[..], ty.clone()));
+            Ok((atom::ok(), imports_to_term(env, parsed)).encode(env))
+        }
+        Err(e) => Ok((atom::error(), e.to_string()).encode(env)),
+    }
+}
+
+fn compile_unloaded(
+    file_name: String,
+    bin: &[u8],
+    config: &config::Config,
+) -> Result<Module, Box<dyn Error>> {
+    let engine = Engine::new(&aux::gen_config(config)?)?;
+    aux::compile_module(&engine, file_name, bin)
+}
+
+fn module_imports_to_term<'a>(env: Env<'a>, module: &Module) -> Term<'a> {
+    imports_to_term(
+        env,
+        module.imports().map(|v| (v.module(), v.name(), v.ty())),
+    )
+}
+
+fn imports_to_term<'a, 'b>(
+    env: Env<'a>,
+    types: impl Iterator<Item = (&'b str, &'b str, ExternType)>,
+) -> Term<'a> {
+    let mut _imports: Vec<Term> = Vec::new();
+    for (module, name, ty) in types {
+        let (kind, ty) = aux::extern_type_to_term(env, &ty);
+        _imports.push((module, name, kind, ty).encode(env));
+    }
+    _imports.encode(env)
+}
+
+#[rustler::nif]
+fn describe<'a>(env: Env<'a>, tid: i64) -> Result<Term<'a>, RustlerError> {
+    if let Some(session) = SESSIONS.read().unwrap().get(&tid) {
+        Ok((atom::ok(), module_exports_to_term(env, &session.module)?).encode(env))
+    } else {
+        Ok((
+            atom::error(),
+            "Wasmtime.load(payload) hasn't been called yet",
+        )
+            .encode(env))
+    }
+}
+
+/// Compiles a module without instantiating it, and describes its exports.
+#[rustler::nif(schedule = "DirtyCpu")]
+fn module_describe<'a>(
+    env: Env<'a>,
+    file_name: String,
+    bin: Vec<u8>,
+    config: Term<'a>,
+) -> Result<Term<'a>, RustlerError> {
+    let config = match config::Config::decode(config) {
+        Ok(v) => v,
+        Err(e) => return Ok((atom::error(), e).encode(env)),
+    };
+    let module = match compile_unloaded(file_name, &bin, &config) {
+        Ok(v) => v,
+        Err(e) => return Ok((atom::error(), e.to_string()).encode(env)),
+    };
+    Ok((atom::ok(), module_exports_to_term(env, &module)?).encode(env))
+}
+
+fn module_exports_to_term<'a>(env: Env<'a>, module: &Module) -> Result<Term<'a>, RustlerError> {
+    let mut _exports: Vec<Term> = Vec::new();
+    for v in module.exports() {
+        let ty = v.ty();
+        let (kind, _) = aux::extern_type_to_term(env, &ty);
+        _exports.push((v.name(), kind, aux::extern_type_to_map(env, &ty)?).encode(env));
+    }
+    Ok(_exports.encode(env))
+}
+
+/// Validates a module's bytes against the features enabled by `config`, without
+/// compiling it.
+#[rustler::nif(schedule = "DirtyCpu")]
+fn validate<'a>(env: Env<'a>, bin: Binary, config: Term<'a>) -> Result<Term<'a>, RustlerError> {
+    let config = match config::Config::decode(config) {
+        Ok(v) => v,
+        Err(e) => return Ok((atom::error(), e).encode(env)),
+    };
+    let engine = match aux::gen_config(&config).and_then(|c| Ok(Engine::new(&c)?)) {
+        Ok(v) => v,
+        Err(e) => return Ok((atom::error(), e.to_string()).encode(env)),
+    };
+    match Module::validate(&engine, bin.as_slice()) {
+        Ok(_) => Ok(atom::ok().encode(env)),
+        Err(e) => {
+            let (offset, message) = match e.downcast_ref::<wasmparser::BinaryReaderError>() {
+                Some(v) => (Some(v.offset()), v.message().to_string()),
+                None => (None, e.to_string()),
+            };
+            let error = Term::map_from_arrays(
+                env,
+                &[atom::offset(), atom::message()],
+                &[offset.encode(env), message.encode(env)],
+            )?;
+            Ok((atom::error(), error).encode(env))
+        }
+    }
+}
+
+#[rustler::nif(schedule = "DirtyCpu")]
+fn wat2wasm<'a>(env: Env<'a>, text: String) -> Result<Term<'a>, RustlerError> {
+    match aux::wat_to_wasm(&text) {
+        Ok(bytes) => {
+            let mut bin = match OwnedBinary::new(bytes.len()) {
+                Some(v) => v,
+                None => return Ok((atom::error(), "failed to allocate binary").encode(env)),
+            };
+            bin.as_mut_slice().copy_from_slice(&bytes);
+            Ok((atom::ok(), bin.release(env)).encode(env))
+        }
+        Err((line, column, message)) => {
+            let error = Term::map_from_arrays(
+                env,
+                &[atom::line(), atom::column(), atom::message()],
+                &[line.encode(env), column.encode(env), message.encode(env)],
+            )?;
+            Ok((atom::error(), error).encode(env))
+        }
+    }
+}
+
+#[rustler::nif(schedule = "DirtyCpu")]
+fn wasm2wat<'a>(env: Env<'a>, bin: Binary) -> Result<Term<'a>, RustlerError> {
+    match wasmprinter::print_bytes(bin.as_slice()) {
+        Ok(text) => Ok((atom::ok(), text).encode(env)),
+        Err(e) => Ok((atom::error(), e.to_string()).encode(env)),
+    }
+}
+
+#[rustler::nif(schedule = "DirtyCpu")]
+fn custom_sections<'a>(env: Env<'a>, bin: Binary, name: String) -> Result<Term<'a>, RustlerError> {
+    let sections = match aux::custom_sections(bin.as_slice(), &name) {
+        Ok(v) => v,
+        Err(e) => return Ok((atom::error(), e.to_string()).encode(env)),
+    };
+    let mut _sections: Vec<Term> = Vec::with_capacity(sections.len());
+    for data in sections {
+        let mut bin = match OwnedBinary::new(data.len()) {
+            Some(v) => v,
+            None => return Ok((atom::error(), "failed to allocate binary").encode(env)),
+        };
+        bin.as_mut_slice().copy_from_slice(&data);
+        _sections.push(bin.release(env).encode(env));
+    }
+    Ok((atom::ok(), _sections).encode(env))
+}
+
+#[rustler::nif(schedule = "DirtyCpu")]
+fn function_names<'a>(env: Env<'a>, bin: Binary) -> Result<Term<'a>, RustlerError> {
+    match aux::function_names(bin.as_slice()) {
+        Ok(v) => Ok((atom::ok(), v).encode(env)),
+        Err(e) => Ok((atom::error(), e.to_string()).encode(env)),
+    }
+}
+
+#[rustler::nif]
+fn configure_pool<'a>(
+    env: Env<'a>,
+    pool_size: Option<usize>,
+    queue_depth: Option<usize>,
+) -> Result<Term<'a>, RustlerError> {
+    match runtime::configure(pool_size, queue_depth) {
+        Ok(_) => Ok((atom::ok()).encode(env)),
+        Err(e) => Ok((atom::error(), e).encode(env)),
+    }
+}
+
+#[rustler::nif]
+fn pool_stats<'a>(env: Env<'a>) -> Result<Term<'a>, RustlerError> {
+    let stats = runtime::stats();
+    Term::map_from_arrays(
+        env,
+        &[
+            atom::active(),
+            atom::queued(),
+            atom::pool_size(),
+            atom::queue_depth(),
+        ],
+        &[
+            stats.active.encode(env),
+            stats.queued.encode(env),
+            stats.pool_size.encode(env),
+            stats.queue_depth.encode(env),
+        ],
+    )
+}
+
+#[rustler::nif]
+fn unload<'a>(env: Env<'a>, tid: i64) -> Result<Term<'a>, RustlerError> {
+    if let Some(session) = SESSIONS.write().unwrap().remove(&tid) {
+        session.close();
+    }
+    Ok((atom::ok()).encode(env))
+}
+
+fn caller_request(tid: i64, call_id: u64, request: CallerRequest) -> Result<CallerReply, String> {
+    let session = match session::get_session(tid) {
+        Some(v) => v,
+        None => return Err("Wasmtime.load(payload) hasn't been called yet".into()),
+    };
+    let sender = match session.pending.get(call_id) {
+        Some(v) => v,
+        None => return Err("caller is only valid during its import invocation".into()),
+    };
+    let (reply_sender, reply_receiver) = crossbeam::channel::bounded(1);
+    if sender.send(HostMsg::Caller(request, reply_sender)).is_err() {
+        return Err("caller is only valid during its import invocation".into());
+    }
+    match reply_receiver.recv() {
+        Ok(v) => v,
+        Err(_) => Err("caller is only valid during its import invocation".into()),
+    }
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn caller_read_memory<'a>(
+    env: Env<'a>,
+    tid: i64,
+    call_id: u64,
+    offset: usize,
+    len: usize,
+) -> Result<Term<'a>, RustlerError> {
+    match caller_request(tid, call_id, CallerRequest::ReadMemory(offset, len)) {
+        Ok(CallerReply::Bytes(bytes)) => {
+            let mut bin = match OwnedBinary::new(bytes.len()) {
+                Some(v) => v,
+                None => return Ok((atom::error(), "failed to allocate binary").encode(env)),
+            };
+            bin.as_mut_slice().copy_from_slice(&bytes);
+            Ok((atom::ok(), bin.release(env)).encode(env))
+        }
+        Ok(_) => Ok((atom::error(), "unexpected caller reply").encode(env)),
+        Err(e) => Ok((atom::error(), e).encode(env)),
+    }
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn caller_write_memory<'a>(
+    env: Env<'a>,
+    tid: i64,
+    call_id: u64,
+    offset: usize,
+    data: Binary,
+) -> Result<Term<'a>, RustlerError> {
+    let request = CallerRequest::WriteMemory(offset, data.as_slice().to_vec());
+    match caller_request(tid, call_id, request) {
+        Ok(CallerReply::Written) => Ok((atom::ok()).encode(env)),
+        Ok(_) => Ok((atom::error(), "unexpected caller reply").encode(env)),
+        Err(e) => Ok((atom::error(), e).encode(env)),
+    }
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn caller_get_export<'a>(
+    env: Env<'a>,
+    tid: i64,
+    call_id: u64,
+    name: String,
+) -> Result<Term<'a>, RustlerError> {
+    match caller_request(tid, call_id, CallerRequest::GetExport(name)) {
+        Ok(CallerReply::Export(kind)) => {
+            let kind = match kind {
+                "func" => atom::func(),
+                "global" => atom::global(),
+                "table" => atom::table(),
+                _ => atom::memory(),
+            };
+            Ok((atom::ok(), kind).encode(env))
+        }
+        Ok(_) => Ok((atom::error(), "unexpected caller reply").encode(env)),
+        Err(e) => Ok((atom::error(), e).encode(env)),
+    }
+}
@@ -0,0 +1,197 @@
+use lazy_static::lazy_static;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use tokio::runtime::{Builder, Runtime};
+use tokio::sync::{Semaphore, SemaphorePermit};
+use wasmtime::{Engine, Store};
+
+const DEFAULT_POOL_SIZE: usize = 1024;
+const DEFAULT_QUEUE_DEPTH: usize = 4096;
+
+/// How often guests running on the pool yield to let other calls and timers run.
+const EPOCH_TICK: Duration = Duration::from_millis(10);
+
+lazy_static! {
+    /// Runs wasm calls on a fixed number of worker threads. Engines are async, so a
+    /// call waiting on an Elixir host function is suspended on its fiber instead of
+    /// holding one of them.
+    pub static ref RUNTIME: Runtime = Builder::new_multi_thread()
+        .worker_threads(worker_threads())
+        .thread_name("wasmtime_ex")
+        .enable_time()
+        .build()
+        .expect("failed to start the wasm runtime");
+
+    /// One permit per execution slot of the pool.
+    static ref SLOTS: Semaphore = Semaphore::new(DEFAULT_POOL_SIZE);
+
+    /// Serializes resizes of `SLOTS`, and counts the permits a shrink still has to
+    /// take back from running executions as they release their slot.
+    static ref RESIZE: Mutex<usize> = Mutex::new(0);
+
+    /// Engines whose epoch is ticking, along with the guard keeping them in the list.
+    static ref EPOCH_ENGINES: Mutex<Vec<(Weak<EpochGuard>, Engine)>> = {
+        std::thread::Builder::new()
+            .name("wasmtime_ex_epoch".into())
+            .spawn(tick_epochs)
+            .expect("failed to start the epoch ticker");
+        Mutex::new(Vec::new())
+    };
+}
+
+static POOL_SIZE: AtomicUsize = AtomicUsize::new(DEFAULT_POOL_SIZE);
+static QUEUE_DEPTH: AtomicUsize = AtomicUsize::new(DEFAULT_QUEUE_DEPTH);
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+static QUEUED: AtomicUsize = AtomicUsize::new(0);
+
+fn worker_threads() -> usize {
+    match std::thread::available_parallelism() {
+        Ok(v) => v.get(),
+        Err(_) => 4,
+    }
+}
+
+/// Returned by `submit` when every slot is taken and the queue is full.
+#[derive(Debug)]
+pub struct Overloaded;
+
+pub struct Stats {
+    pub active: usize,
+    pub queued: usize,
+    pub pool_size: usize,
+    pub queue_depth: usize,
+}
+
+/// Counts an execution as active for as long as it's alive, even if it panics.
+struct ActiveGuard;
+
+impl ActiveGuard {
+    fn new() -> Self {
+        ACTIVE.fetch_add(1, Ordering::SeqCst);
+        Self
+    }
+}
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        ACTIVE.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A slot of the pool held by an execution. Releasing it pays back the debt of a
+/// pending shrink before making it available again.
+struct Slot(Option<SemaphorePermit<'static>>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let mut debt = RESIZE.lock().unwrap();
+        if let Some(permit) = self.0.take() {
+            if *debt > 0 {
+                *debt -= 1;
+                permit.forget();
+            }
+        }
+    }
+}
+
+/// Runs `task` on the pool once one of its slots is free. Up to the queue depth of
+/// tasks may wait for a slot, beyond which `task` is rejected.
+pub fn submit<F>(task: F) -> Result<(), Overloaded>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let queued = QUEUED.fetch_add(1, Ordering::SeqCst);
+    if queued >= QUEUE_DEPTH.load(Ordering::SeqCst) + SLOTS.available_permits() {
+        QUEUED.fetch_sub(1, Ordering::SeqCst);
+        return Err(Overloaded);
+    }
+    RUNTIME.spawn(async move {
+        let slot = Slot(SLOTS.acquire().await.ok());
+        QUEUED.fetch_sub(1, Ordering::SeqCst);
+        let _active = ActiveGuard::new();
+        task.await;
+        drop(slot);
+    });
+    Ok(())
+}
+
+/// Changes the number of slots and the queue depth, leaving `None`s as they are.
+/// Executions already running keep their slot when the pool shrinks.
+pub fn configure(pool_size: Option<usize>, queue_depth: Option<usize>) -> Result<(), String> {
+    if let Some(0) = pool_size {
+        return Err("pool_size must be positive".into());
+    }
+    if let Some(depth) = queue_depth {
+        QUEUE_DEPTH.store(depth, Ordering::SeqCst);
+    }
+    if let Some(size) = pool_size {
+        let mut debt = RESIZE.lock().unwrap();
+        let current = POOL_SIZE.swap(size, Ordering::SeqCst);
+        if size > current {
+            // Slots a previous shrink hasn't taken back yet are simply kept.
+            let grow = size - current;
+            let kept = grow.min(*debt);
+            *debt -= kept;
+            SLOTS.add_permits(grow - kept);
+        } else if size < current {
+            // Free slots are taken back right away, and busy ones once released.
+            let excess = current - size;
+            let free = excess.min(SLOTS.available_permits());
+            let taken = match SLOTS.try_acquire_many(free as u32) {
+                Ok(permits) => {
+                    permits.forget();
+                    free
+                }
+                Err(_) => 0,
+            };
+            *debt += excess - taken;
+        }
+    }
+    Ok(())
+}
+
+/// Keeps the epoch of an engine ticking for as long as it's alive.
+pub struct EpochGuard;
+
+/// Makes `engine`'s epoch advance every `EPOCH_TICK` until the returned guard and
+/// its clones are dropped. The ticks come from a thread of their own, since every
+/// worker thread of the pool may be busy running guests.
+pub fn start_epoch(engine: &Engine) -> Arc<EpochGuard> {
+    let guard = Arc::new(EpochGuard);
+    EPOCH_ENGINES
+        .lock()
+        .unwrap()
+        .push((Arc::downgrade(&guard), engine.clone()));
+    guard
+}
+
+/// Makes guests running in `store` yield back to the pool on every epoch tick,
+/// so CPU-bound calls don't hold a worker thread for their whole duration.
+pub fn yield_on_epoch(store: &mut Store<()>) {
+    store.set_epoch_deadline(1);
+    store.epoch_deadline_async_yield_and_update(1);
+}
+
+fn tick_epochs() {
+    loop {
+        std::thread::sleep(EPOCH_TICK);
+        EPOCH_ENGINES.lock().unwrap().retain(|(guard, engine)| {
+            if guard.strong_count() == 0 {
+                return false;
+            }
+            engine.increment_epoch();
+            true
+        });
+    }
+}
+
+pub fn stats() -> Stats {
+    Stats {
+        active: ACTIVE.load(Ordering::SeqCst),
+        queued: QUEUED.load(Ordering::SeqCst),
+        pool_size: POOL_SIZE.load(Ordering::SeqCst),
+        queue_depth: QUEUE_DEPTH.load(Ordering::SeqCst),
+    }
+}
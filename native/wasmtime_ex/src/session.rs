@@ -1,32 +1,171 @@
+use crate::config::Config;
+use crate::runtime::EpochGuard;
 use lazy_static::lazy_static;
 use std::collections::HashMap;
-use std::sync::RwLock;
-use wasmtime::{Module, Val, ValType};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use wasmtime::{Func, GlobalType, Instance, MemoryType, Module, Store, TableType, Val, ValType};
 
 lazy_static! {
-    pub static ref SESSIONS: RwLock<HashMap<i64, Box<Session>>> = RwLock::new(HashMap::new());
+    pub static ref SESSIONS: RwLock<HashMap<i64, Arc<Session>>> = RwLock::new(HashMap::new());
 }
 
+static NEXT_CALL_ID: AtomicU64 = AtomicU64::new(1);
+
+/// A store shared by every session instantiated into it. Sessions importing
+/// another session's exports join its store, since externs can't cross stores.
+/// It's held for the whole duration of a call, including while the call is
+/// suspended on a host function.
+pub type SharedStore = Arc<tokio::sync::Mutex<Store<()>>>;
+
 pub struct Session {
     pub module: Module,
-    pub fchs: HashMap<i64, (crossbeam::Sender<Vec<SVal>>, crossbeam::Receiver<Vec<SVal>>)>,
-    pub exports: HashMap<String, Vec<SValType>>,
+    pub config: Config,
+    pub epoch: Arc<EpochGuard>,
+    pub store: SharedStore,
+    pub instance: Instance,
+    /// Exported functions and their parameter types, by name.
+    pub exports: HashMap<String, (Func, Vec<SValType>)>,
+    pub pending: Arc<PendingCalls>,
 }
 
 impl Session {
     pub fn new(
         module: Module,
-        fchs: HashMap<i64, (crossbeam::Sender<Vec<SVal>>, crossbeam::Receiver<Vec<SVal>>)>,
-        exports: HashMap<String, Vec<SValType>>,
+        config: Config,
+        epoch: Arc<EpochGuard>,
+        store: SharedStore,
+        instance: Instance,
+        exports: HashMap<String, (Func, Vec<SValType>)>,
+        pending: Arc<PendingCalls>,
     ) -> Self {
         Self {
             module,
-            fchs,
+            config,
+            epoch,
+            store,
+            instance,
             exports,
+            pending,
+        }
+    }
+
+    pub fn close(&self) {
+        self.pending.close();
+    }
+}
+
+/// Import invocations waiting on their Elixir host function.
+#[derive(Default)]
+pub struct PendingCalls {
+    calls: Mutex<HashMap<u64, UnboundedSender<HostMsg>>>,
+    closed: AtomicBool,
+}
+
+impl PendingCalls {
+    /// Registers an in-flight import invocation and returns its call id and the
+    /// receiver its results and caller requests will be delivered on. Once
+    /// closed the receiver is already disconnected.
+    pub fn register(&self) -> (u64, UnboundedReceiver<HostMsg>) {
+        let call_id = NEXT_CALL_ID.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = unbounded_channel();
+        if !self.closed.load(Ordering::SeqCst) {
+            self.calls.lock().unwrap().insert(call_id, sender);
         }
+        (call_id, receiver)
+    }
+
+    pub fn take(&self, call_id: u64) -> Option<UnboundedSender<HostMsg>> {
+        self.calls.lock().unwrap().remove(&call_id)
+    }
+
+    pub fn get(&self, call_id: u64) -> Option<UnboundedSender<HostMsg>> {
+        self.calls.lock().unwrap().get(&call_id).cloned()
+    }
+
+    /// Drops every pending sender, so import invocations still waiting on a
+    /// reply are woken up.
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.calls.lock().unwrap().clear();
+    }
+}
+
+pub fn get_session(tid: i64) -> Option<Arc<Session>> {
+    SESSIONS.read().unwrap().get(&tid).cloned()
+}
+
+/// An exported function looked up once, along with its signature, so calls
+/// through it skip the lookup by name.
+pub struct FuncHandle {
+    pub tid: i64,
+    pub session: Arc<Session>,
+    pub func: Func,
+    pub params: Vec<ValType>,
+    pub results: Vec<ValType>,
+}
+
+#[derive(Debug)]
+pub struct FuncImport {
+    pub name: String,
+    pub timeout: Option<Duration>,
+}
+
+/// Memories, globals and tables provided by the host, in the order the module
+/// imports each kind, and the sessions linked by import module name.
+#[derive(Default)]
+pub struct ExternImports {
+    pub memories: Vec<MemoryImport>,
+    pub globals: Vec<(GlobalType, Val)>,
+    pub tables: Vec<TableType>,
+    pub links: HashMap<String, i64>,
+}
+
+pub enum MemoryImport {
+    New(MemoryType),
+    /// A memory exported by another session, by session id and export name.
+    Export(i64, String),
+}
+
+impl ExternImports {
+    /// Ids of the sessions whose exports are imported.
+    pub fn sessions(&self) -> Vec<i64> {
+        self.memories
+            .iter()
+            .filter_map(|m| match m {
+                MemoryImport::Export(tid, _) => Some(*tid),
+                _ => None,
+            })
+            .chain(self.links.values().cloned())
+            .collect()
     }
 }
 
+/// Messages delivered to an import invocation blocked on its Elixir host function.
+pub enum HostMsg {
+    Results(Vec<SVal>),
+    Caller(
+        CallerRequest,
+        crossbeam::Sender<Result<CallerReply, String>>,
+    ),
+}
+
+/// Requests a host function makes through its `Wasmtime.Caller` context. They're
+/// served on the wasm thread, which is the only one allowed to touch the `Caller`.
+pub enum CallerRequest {
+    ReadMemory(usize, usize),
+    WriteMemory(usize, Vec<u8>),
+    GetExport(String),
+}
+
+pub enum CallerReply {
+    Bytes(Vec<u8>),
+    Written,
+    Export(&'static str),
+}
+
 #[derive(Debug)]
 pub struct SVal {
     pub v: Val,
@@ -1,6 +1,8 @@
 defmodule WasmtimeTest do
   use ExUnit.Case
   doctest Wasmtime
+  doctest Wasmtime.Module
+  doctest Wasmtime.Pool
 
   test "load wat from bytes" do
     mod = ~S/
@@ -85,6 +87,250 @@ defmodule WasmtimeTest do
     ^expected_res = Enum.reduce(stream, 0, fn {:ok, {:ok, [num]}}, acc -> num + acc end)
   end
 
+  test "CPU-bound calls yield worker threads" do
+    spin = ~S/
+    (module
+      (func (export "spin") (param i64) (result i64)
+        (local i64)
+        (block
+          (loop
+            (br_if 1 (i64.ge_u (local.get 1) (local.get 0)))
+            (local.set 1 (i64.add (local.get 1) (i64.const 1)))
+            (br 0)))
+        (local.get 1))
+    )
+    /
+
+    spinners =
+      for _ <- 1..(System.schedulers_online() * 2) do
+        {:ok, pid} = Wasmtime.load(%Wasmtime.FromBytes{bytes: spin})
+        Task.async(fn -> :timer.tc(fn -> Wasmtime.call_func(pid, "spin", [300_000_000]) end) end)
+      end
+
+    Process.sleep(50)
+    {:ok, adder} = Wasmtime.load(%Wasmtime.FromFile{file_path: "test/data/adder.wat"})
+    {quick, {:ok, [3]}} = :timer.tc(fn -> Wasmtime.call_func(adder, "add", [1, 2]) end)
+
+    times =
+      Enum.map(spinners, fn task ->
+        {time, {:ok, [300_000_000]}} = Task.await(task, 30_000)
+        time
+      end)
+
+    assert quick < Enum.min(times)
+  end
+
+  test "calls suspended in imports don't hold worker threads" do
+    mod = ~S/
+    (module
+      (import "env" "wait" (func $wait (result i32)))
+      (func (export "run") (result i32)
+        call $wait)
+    )
+    /
+
+    pids =
+      for _ <- 1..64 do
+        {:ok, pid} =
+          Wasmtime.load(%Wasmtime.FromBytes{
+            bytes: mod,
+            func_imports: [{fn -> Process.sleep(500) && 1 end, [], [:i32]}]
+          })
+
+        pid
+      end
+
+    {elapsed, results} =
+      :timer.tc(fn ->
+        pids
+        |> Task.async_stream(&Wasmtime.call_func(&1, "run"), max_concurrency: 64)
+        |> Enum.map(fn {:ok, res} -> res end)
+      end)
+
+    true = Enum.all?(results, &(&1 == {:ok, [1]}))
+    true = elapsed < 2_000_000
+  end
+
+  test "pool backpressure" do
+    mod = ~S/
+    (module
+      (import "env" "wait" (func $wait (result i32)))
+      (func (export "run") (result i32)
+        call $wait)
+    )
+    /
+
+    test_pid = self()
+
+    pids =
+      for _ <- 1..3 do
+        {:ok, pid} =
+          Wasmtime.load(%Wasmtime.FromBytes{
+            bytes: mod,
+            func_imports: [
+              {fn ->
+                 send(test_pid, :waiting)
+
+                 receive do
+                   :go -> 1
+                 end
+               end, [], [:i32]}
+            ]
+          })
+
+        pid
+      end
+
+    %{pool_size: pool_size, queue_depth: queue_depth} = Wasmtime.pool_stats()
+    :ok = Wasmtime.configure_pool(pool_size: 1, queue_depth: 1)
+
+    try do
+      [a, b, c] = pids
+      task_a = Task.async(fn -> Wasmtime.call_func(a, "run") end)
+      assert_receive :waiting
+      task_b = Task.async(fn -> Wasmtime.call_func(b, "run") end)
+      Process.sleep(100)
+
+      %{active: 1, queued: 1} = Wasmtime.pool_stats()
+      {:error, :overloaded} = Wasmtime.call_func(c, "run")
+
+      send(a, :go)
+      {:ok, [1]} = Task.await(task_a)
+      assert_receive :waiting
+      send(b, :go)
+      {:ok, [1]} = Task.await(task_b)
+    after
+      :ok = Wasmtime.configure_pool(pool_size: pool_size, queue_depth: queue_depth)
+    end
+  end
+
+  test "growing the pool back cancels a pending shrink" do
+    mod = ~S/
+    (module
+      (import "env" "wait" (func $wait (result i32)))
+      (func (export "run") (result i32)
+        call $wait)
+    )
+    /
+
+    test_pid = self()
+
+    [a, b] =
+      for _ <- 1..2 do
+        {:ok, pid} =
+          Wasmtime.load(%Wasmtime.FromBytes{
+            bytes: mod,
+            func_imports: [
+              {fn ->
+                 send(test_pid, :waiting)
+
+                 receive do
+                   :go -> 1
+                 end
+               end, [], [:i32]}
+            ]
+          })
+
+        pid
+      end
+
+    run_both = fn ->
+      task_a = Task.async(fn -> Wasmtime.call_func(a, "run") end)
+      assert_receive :waiting
+      task_b = Task.async(fn -> Wasmtime.call_func(b, "run") end)
+      assert_receive :waiting
+      {task_a, task_b}
+    end
+
+    %{pool_size: pool_size, queue_depth: queue_depth} = Wasmtime.pool_stats()
+    :ok = Wasmtime.configure_pool(pool_size: 2)
+
+    try do
+      {task_a, task_b} = run_both.()
+      # Both slots are busy, so the shrink can't take one back yet.
+      :ok = Wasmtime.configure_pool(pool_size: 1)
+      :ok = Wasmtime.configure_pool(pool_size: 2)
+      send(a, :go)
+      send(b, :go)
+      [{:ok, [1]}, {:ok, [1]}] = Task.await_many([task_a, task_b])
+
+      {task_a, task_b} = run_both.()
+      %{active: 2, pool_size: 2} = Wasmtime.pool_stats()
+      send(a, :go)
+      send(b, :go)
+      [{:ok, [1]}, {:ok, [1]}] = Task.await_many([task_a, task_b])
+    after
+      :ok = Wasmtime.configure_pool(pool_size: pool_size, queue_depth: queue_depth)
+    end
+  end
+
+  test "call_func_xt doesn't block the instance's GenServer" do
+    mod = ~S/
+    (module
+      (func (export "spin") (param i64) (result i64)
+        (local i64)
+        (block
+          (loop
+            (br_if 1 (i64.ge_u (local.get 1) (local.get 0)))
+            (local.set 1 (i64.add (local.get 1) (i64.const 1)))
+            (br 0)))
+        (local.get 1))
+    )
+    /
+
+    {:ok, pid} = Wasmtime.load(%Wasmtime.FromBytes{bytes: mod})
+    task = Task.async(fn -> Wasmtime.call_func_xt(pid, "spin", [200_000_000]) end)
+    Process.sleep(10)
+    {:ok, [{"spin", :func}]} = Wasmtime.exports(pid)
+    {:error, :busy} = Wasmtime.call_func_xt(pid, "spin", [1])
+    {:ok, spin} = Wasmtime.func_handle(pid, "spin")
+    {:error, :busy} = Wasmtime.call_handle(spin, [1])
+    {:ok, [200_000_000]} = Task.await(task, 30_000)
+    {:ok, [1]} = Wasmtime.call_handle(spin, [1])
+  end
+
+  test "func handles" do
+    {:ok, pid} = Wasmtime.load(%Wasmtime.FromFile{file_path: "test/data/adder.wat"})
+    {:ok, add} = Wasmtime.func_handle(pid, "add")
+
+    {:ok, [3]} = Wasmtime.call_handle(add, [1, 2])
+    {:ok, [-1]} = Wasmtime.call_handle(add, [1, -2])
+    {:error, "function \"sub\" not found"} = Wasmtime.func_handle(pid, "sub")
+
+    GenServer.stop(pid)
+    Process.sleep(50)
+    {:error, "the instance has been unloaded"} = Wasmtime.call_handle(add, [1, 2])
+  end
+
+  test "pool of instances" do
+    mod = ~S/
+    (module
+      (global $calls (mut i32) (i32.const 0))
+      (func (export "count") (result i32)
+        (global.set $calls (i32.add (global.get $calls) (i32.const 1)))
+        (global.get $calls))
+    )
+    /
+
+    {:ok, pool} = Wasmtime.Pool.load(%Wasmtime.FromBytes{bytes: mod}, 4)
+    4 = pool |> Wasmtime.Pool.pids() |> length()
+
+    counts =
+      1..8
+      |> Task.async_stream(fn _ -> Wasmtime.Pool.call_func(pool, "count") end)
+      |> Enum.map(fn {:ok, {:ok, [count]}} -> count end)
+      |> Enum.sort()
+
+    [1, 1, 1, 1, 2, 2, 2, 2] = counts
+    {:ok, [3]} = Wasmtime.Pool.call_func_xt(pool, "count")
+
+    :ok = Wasmtime.Pool.stop(pool)
+
+    {:links, links} = Process.info(self(), :links)
+    {:error, _} = Wasmtime.Pool.load(%Wasmtime.FromBytes{bytes: "(module (func (invalid)))"}, 2)
+    {:links, ^links} = Process.info(self(), :links)
+  end
+
   test "load from wasm file" do
     {:ok, pid} = Wasmtime.load(%Wasmtime.FromFile{file_path: "test/data/wasmapp/wasmapp_bg.wasm"})
     {:ok, {[:i32, :i32], [:i32]}} = Wasmtime.get_func(pid, "add")
@@ -110,6 +356,166 @@ defmodule WasmtimeTest do
     {:ok, [8_589_934_593]} = Wasmtime.call_func_xt(pid, "add", [8_589_934_592, 1])
   end
 
+  test "call_func_xt with imports and float params" do
+    mod = ~S/
+    (module
+      (import "wasmtime_ex" "monotonic_now" (func $monotonic_now (result i64)))
+      (func (export "now") (result i64) (call $monotonic_now))
+      (func (export "mul") (param f32 f64) (result f64)
+        (f64.mul (f64.promote_f32 (local.get 0)) (local.get 1)))
+    )
+    /
+
+    {:ok, pid} = Wasmtime.load(%Wasmtime.FromBytes{bytes: mod, builtins: true})
+    {:ok, [now]} = Wasmtime.call_func_xt(pid, "now")
+    assert now >= 0
+    {:ok, [7.5]} = Wasmtime.call_func_xt(pid, "mul", [2.5, 3.0])
+    {:error, {:bad_arity, 2, 1}} = Wasmtime.call_func_xt(pid, "mul", [2.5])
+    {:error, {:bad_arity, 2, 1}} = Wasmtime.call_func(pid, "mul", [2.5])
+  end
+
+  test "unsigned integers" do
+    mod = ~S/
+    (module
+      (func (export "max") (param i32 i64) (result i32 i64)
+        (local.get 0)
+        (local.get 1))
+    )
+    /
+
+    {:ok, pid} = Wasmtime.load(%Wasmtime.FromBytes{bytes: mod})
+    {:ok, max} = Wasmtime.func_handle(pid, "max")
+    u64_max = 18_446_744_073_709_551_615
+
+    {:ok, [-1, -1]} = Wasmtime.call_func(pid, "max", [4_294_967_295, u64_max])
+    {:ok, [4_294_967_295, ^u64_max]} = Wasmtime.call_func(pid, "max", [-1, -1], unsigned: true)
+    {:ok, [4_294_967_295, ^u64_max]} = Wasmtime.call_func_xt(pid, "max", [-1, -1], unsigned: true)
+    {:ok, [1, 2]} = Wasmtime.call_handle(max, [1, 2], unsigned: true)
+
+    {:error, {:bad_argument, 1, :i64, 18_446_744_073_709_551_616}} =
+      Wasmtime.call_func(pid, "max", [0, u64_max + 1])
+
+    {:error, {:bad_argument, 0, :i32, -2_147_483_649}} =
+      Wasmtime.call_handle(max, [-2_147_483_649, 0])
+  end
+
+  test "call many" do
+    mod = ~S/
+    (module
+      (global $total (mut i32) (i32.const 0))
+      (func (export "add") (param i32) (result i32)
+        (global.set $total (i32.add (global.get $total) (local.get 0)))
+        (global.get $total))
+      (func (export "trap") unreachable)
+    )
+    /
+
+    {:ok, pid} = Wasmtime.load(%Wasmtime.FromBytes{bytes: mod})
+
+    {:ok, [{:ok, [1]}, {:ok, [3]}, {:error, _}]} =
+      Wasmtime.call_many(pid, [{"add", [1]}, {"add", [2]}, {"trap", []}, {"add", [3]}])
+
+    {:ok, [{:error, _}, {:ok, [6]}, {:ok, [-1]}]} =
+      Wasmtime.call_many(pid, [{"trap", []}, {"add", [3]}, {"add", [-7]}], on_trap: :continue)
+
+    {:ok, [{:ok, [4_294_967_295]}]} = Wasmtime.call_many(pid, [{"add", [0]}], unsigned: true)
+    {:ok, []} = Wasmtime.call_many(pid, [])
+
+    {:error, {1, "function \"sub\" not found"}} =
+      Wasmtime.call_many(pid, [{"add", [1]}, {"sub", [1]}])
+
+    {:error, {0, {:bad_arity, 1, 0}}} = Wasmtime.call_many(pid, [{"add", []}])
+    {:ok, [{:ok, [-1]}]} = Wasmtime.call_many(pid, [{"add", [0]}])
+  end
+
+  test "call with binaries" do
+    mod = ~S/
+    (module
+      (memory (export "memory") 1)
+      (global $heap (mut i32) (i32.const 1024))
+      (global $freed (mut i32) (i32.const 0))
+      (func $alloc (export "alloc") (param $len i32) (result i32)
+        (global.get $heap)
+        (global.set $heap (i32.add (global.get $heap) (local.get $len))))
+      (func (export "dealloc") (param i32 i32)
+        (global.set $freed (i32.add (global.get $freed) (i32.const 1))))
+      (func (export "freed") (result i32) (global.get $freed))
+      (func (export "bogus") (result i32 i32) (i32.const -65536) (i32.const 16))
+      (func (export "size") (param i32 i32 i32) (result i32)
+        (i32.add (local.get 1) (local.get 2)))
+      (func (export "scale") (param i32 i32 f64) (result f64)
+        (f64.mul (f64.convert_i32_u (local.get 1)) (local.get 2)))
+      (func (export "concat") (param $a i32) (param $alen i32) (param $b i32) (param $blen i32)
+        (result i32 i32)
+        (local $out i32)
+        (local.set $out (call $alloc (i32.add (local.get $alen) (local.get $blen))))
+        (memory.copy (local.get $out) (local.get $a) (local.get $alen))
+        (memory.copy
+          (i32.add (local.get $out) (local.get $alen))
+          (local.get $b)
+          (local.get $blen))
+        (local.get $out)
+        (i32.add (local.get $alen) (local.get $blen)))
+      (func (export "echo") (param $ptr i32) (param $len i32) (result i64)
+        (local $out i32)
+        (local.set $out (call $alloc (local.get $len)))
+        (memory.copy (local.get $out) (local.get $ptr) (local.get $len))
+        (i64.or
+          (i64.shl (i64.extend_i32_u (local.get $out)) (i64.const 32))
+          (i64.extend_i32_u (local.get $len))))
+    )
+    /
+
+    {:ok, pid} = Wasmtime.load(%Wasmtime.FromBytes{bytes: mod})
+
+    {:ok, "hello world"} =
+      Wasmtime.call_with_binaries(pid, "concat", ["hello ", "world"], returns: :binary)
+
+    {:ok, [3]} = Wasmtime.call_func(pid, "freed")
+    {:ok, "écho"} = Wasmtime.call_with_binaries(pid, "echo", ["écho"], returns: :binary)
+    {:ok, [7]} = Wasmtime.call_with_binaries(pid, "size", ["abcd", 3])
+    {:ok, [6]} = Wasmtime.call_func(pid, "freed")
+
+    {:error, {:bad_arity, 3, 4}} = Wasmtime.call_with_binaries(pid, "size", ["abcd", "x"])
+    {:error, {:bad_argument, 1, :i32, 1.5}} =
+      Wasmtime.call_with_binaries(pid, "size", ["ab", 1.5])
+
+    {:ok, [3.0]} = Wasmtime.call_with_binaries(pid, "scale", ["abc", 1.0])
+    {:error, {:bad_argument, 1, :f64, 2}} = Wasmtime.call_with_binaries(pid, "scale", ["ab", 2])
+
+    {:error, "function \"malloc\" not found"} =
+      Wasmtime.call_with_binaries(pid, "size", ["abcd", 3], alloc: "malloc")
+
+    {:error, _} = Wasmtime.call_with_binaries(pid, "size", ["abcd", 3], returns: :binary)
+
+    {:error, "function \"bogus\" returned bytes out of the bounds of memory"} =
+      Wasmtime.call_with_binaries(pid, "bogus", [], returns: :binary)
+
+    {:ok, [7]} = Wasmtime.call_func(pid, "freed")
+  end
+
+  test "bad arguments" do
+    mod = ~S/
+    (module
+      (func (export "f") (param i32 i64 f32 f64))
+    )
+    /
+
+    {:ok, pid} = Wasmtime.load(%Wasmtime.FromBytes{bytes: mod})
+    {:ok, f} = Wasmtime.func_handle(pid, "f")
+    {:ok, []} = Wasmtime.call_func(pid, "f", [1, 2, 3.0, 4.0])
+
+    {:error, {:bad_arity, 4, 5}} = Wasmtime.call_func(pid, "f", [1, 2, 3.0, 4.0, 5])
+    {:error, {:bad_arity, 4, 0}} = Wasmtime.call_handle(f, [])
+
+    {:error, {:bad_argument, 0, :i32, 4_294_967_296}} =
+      Wasmtime.call_func(pid, "f", [4_294_967_296, 2, 3.0, 4.0])
+
+    {:error, {:bad_argument, 1, :i64, "2"}} = Wasmtime.call_func_xt(pid, "f", [1, "2", 3.0, 4.0])
+    {:error, {:bad_argument, 2, :f32, 3}} = Wasmtime.call_handle(f, [1, 2, 3, 4.0])
+    {:error, {:bad_argument, 3, :f64, nil}} = Wasmtime.call_func(pid, "f", [1, 2, 3.0, nil])
+  end
+
   test "add [:f32, :f32], [:f32]" do
     mod = ~S/
     (module
@@ -196,6 +602,534 @@ defmodule WasmtimeTest do
     {:ok, [32]} = Wasmtime.call_func(pid, "runic", [22])
   end
 
+  test "import func timeout traps" do
+    mod = ~S/
+    (module
+      (import "env" "slow" (func $slow (param i32) (result i32)))
+      (func (export "run") (param i32) (result i32) (call $slow (local.get 0)))
+    )
+    /
+
+    {:ok, pid} =
+      Wasmtime.load(%Wasmtime.FromBytes{
+        bytes: mod,
+        func_imports: [
+          {fn x ->
+             Process.sleep(200)
+             x
+           end, [:i32], [:i32], timeout: 50}
+        ]
+      })
+
+    {:error, {:host_timeout, "slow"}} = Wasmtime.call_func(pid, "run", [1])
+  end
+
+  test "owner death traps calls parked in an import" do
+    mod = ~S/
+    (module
+      (import "env" "park" (func $park (result i32)))
+      (func (export "run") (result i32) (call $park))
+    )
+    /
+
+    test_pid = self()
+
+    {:ok, pid} =
+      Wasmtime.load(%Wasmtime.FromBytes{
+        bytes: mod,
+        func_imports: [
+          {fn caller ->
+             send(test_pid, {:parked, caller})
+             Process.sleep(:infinity)
+           end, [], [:i32], timeout: :infinity}
+        ]
+      })
+
+    Process.unlink(pid)
+    task = Task.async(fn -> Wasmtime.call_func_xt(pid, "run") end)
+    assert_receive {:parked, caller}
+    Process.exit(pid, :kill)
+
+    {:error, {:host_timeout, "park"}} = Task.await(task)
+    {:error, _} = Wasmtime.Native.exfn_reply(caller.id, caller.call_id, [{1, :i32}])
+  end
+
+  test "import func with caller memory access" do
+    mod = ~S/
+    (module
+      (import "env" "upcase" (func $upcase (param i32 i32) (result i32)))
+      (memory (export "memory") 1)
+      (data (i32.const 16) "hello")
+      (func (export "run") (result i32) (call $upcase (i32.const 16) (i32.const 5)))
+      (func (export "first") (result i32) (i32.load8_u (i32.const 16)))
+    )
+    /
+
+    {:ok, pid} =
+      Wasmtime.load(%Wasmtime.FromBytes{
+        bytes: mod,
+        func_imports: [
+          {fn caller, ptr, len ->
+             {:ok, :memory} = Wasmtime.Caller.get_export(caller, "memory")
+             {:error, _} = Wasmtime.Caller.get_export(caller, "missing")
+             {:ok, "hello"} = Wasmtime.Caller.read_memory(caller, ptr, len)
+             :ok = Wasmtime.Caller.write_memory(caller, ptr, "HELLO")
+             {:ok, "HELLO"} = Wasmtime.Caller.read_memory(caller, ptr, len)
+             {:error, _} = Wasmtime.Caller.read_memory(caller, 0, 1_099_511_627_776)
+             len
+           end, [:i32, :i32], [:i32]}
+        ]
+      })
+
+    {:ok, [5]} = Wasmtime.call_func(pid, "run")
+    {:ok, [?H]} = Wasmtime.call_func(pid, "first")
+  end
+
+  test "load unknown host module" do
+    mod = ~S/
+    (module
+      (func (export "add") (param i32 i32) (result i32)
+        local.get 0
+        local.get 1
+        i32.add)
+    )
+    /
+
+    {:error, "host module \"unknown\" not found"} =
+      Wasmtime.load(%Wasmtime.FromBytes{bytes: mod, host_modules: ["unknown"]})
+  end
+
+  test "builtins host module" do
+    mod = ~S/
+    (module
+      (import "wasmtime_ex" "log" (func $log (param i32 i32 i32)))
+      (import "wasmtime_ex" "monotonic_now" (func $monotonic_now (result i64)))
+      (import "wasmtime_ex" "random_bytes" (func $random_bytes (param i32 i32)))
+      (import "" "" (func $compute (param i32) (result i32)))
+      (memory (export "memory") 1)
+      (data (i32.const 0) "hello from wasm")
+      (func (export "log") (call $log (i32.const 1) (i32.const 0) (i32.const 15)))
+      (func (export "now") (result i64) (call $monotonic_now))
+      (func (export "random") (result i64)
+        (call $random_bytes (i32.const 64) (i32.const 8))
+        (i64.load (i32.const 64)))
+      (func (export "random_oob") (call $random_bytes (i32.const 64) (i32.const -1)))
+      (func (export "log_oob") (call $log (i32.const 1) (i32.const 0) (i32.const -1)))
+      (func (export "run") (param i32) (result i32) (call $compute (local.get 0)))
+    )
+    /
+
+    {:ok, pid} =
+      Wasmtime.load(%Wasmtime.FromBytes{
+        bytes: mod,
+        builtins: true,
+        func_imports: [{fn x -> x + 1 end, [:i32], [:i32]}]
+      })
+
+    assert ExUnit.CaptureLog.capture_log(fn ->
+             {:ok, []} = Wasmtime.call_func(pid, "log")
+             Process.sleep(50)
+           end) =~ "hello from wasm"
+
+    {:ok, [now]} = Wasmtime.call_func(pid, "now")
+    assert now >= 0
+    {:ok, [_]} = Wasmtime.call_func(pid, "random")
+    {:error, _} = Wasmtime.call_func(pid, "random_oob")
+    {:error, _} = Wasmtime.call_func(pid, "log_oob")
+    {:ok, [2]} = Wasmtime.call_func(pid, "run", [1])
+  end
+
+  test "import memory, global and table" do
+    mod = ~S/
+    (module
+      (import "env" "memory" (memory 1 2))
+      (import "env" "base" (global $base i32))
+      (import "env" "counter" (global $counter (mut i64)))
+      (import "env" "table" (table 1 funcref))
+      (func (export "size") (result i32) (memory.size))
+      (func (export "base") (result i32) (global.get $base))
+      (func (export "counter") (result i64) (global.get $counter))
+      (func (export "table_size") (result i32) (table.size))
+    )
+    /
+
+    {:ok, pid} =
+      Wasmtime.load(%Wasmtime.FromBytes{
+        bytes: mod,
+        memory_imports: [{1, 2}],
+        global_imports: [{:i32, :const, 1024}, {:i64, :var, 8_589_934_592}],
+        table_imports: [{1, nil}]
+      })
+
+    {:ok, [1]} = Wasmtime.call_func(pid, "size")
+    {:ok, [1024]} = Wasmtime.call_func(pid, "base")
+    {:ok, [8_589_934_592]} = Wasmtime.call_func(pid, "counter")
+    {:ok, [1]} = Wasmtime.call_func(pid, "table_size")
+  end
+
+  test "import memory not provided" do
+    mod = ~S/
+    (module
+      (import "env" "memory" (memory 1))
+    )
+    /
+
+    {:error, "import \"env\" \"memory\" not provided"} =
+      Wasmtime.load(%Wasmtime.FromBytes{bytes: mod})
+  end
+
+  test "import memory exported by another instance" do
+    producer = ~S/
+    (module
+      (memory (export "memory") 1)
+      (func (export "store") (param i32 i32)
+        local.get 0
+        local.get 1
+        i32.store)
+    )
+    /
+
+    consumer = ~S/
+    (module
+      (import "env" "memory" (memory 1))
+      (func (export "load") (param i32) (result i32)
+        local.get 0
+        i32.load)
+    )
+    /
+
+    {:ok, pid_a} = Wasmtime.load(%Wasmtime.FromBytes{bytes: producer})
+
+    {:ok, pid_b} =
+      Wasmtime.load(%Wasmtime.FromBytes{bytes: consumer, memory_imports: [{pid_a, "memory"}]})
+
+    {:ok, []} = Wasmtime.call_func(pid_a, "store", [16, 42])
+    {:ok, [42]} = Wasmtime.call_func(pid_b, "load", [16])
+
+    {:error, "config differs from the one of the instances imported from"} =
+      Wasmtime.load(%Wasmtime.FromBytes{
+        bytes: consumer,
+        memory_imports: [{pid_a, "memory"}],
+        config: %Wasmtime.Config{debug_info: true}
+      })
+  end
+
+  test "link modules by name" do
+    lib = ~S/
+    (module
+      (memory (export "memory") 1)
+      (global (export "base") i32 (i32.const 100))
+      (table (export "table") 1 funcref)
+      (func (export "double") (param i32) (result i32)
+        local.get 0
+        i32.const 2
+        i32.mul)
+    )
+    /
+
+    app = ~S/
+    (module
+      (import "lib" "double" (func $double (param i32) (result i32)))
+      (import "lib" "memory" (memory 1))
+      (import "lib" "base" (global $base i32))
+      (import "lib" "table" (table 1 funcref))
+      (import "env" "inc" (func $inc (param i32) (result i32)))
+      (func (export "run") (param i32) (result i32)
+        global.get $base
+        local.get 0
+        call $double
+        call $inc
+        i32.store
+        global.get $base
+        i32.load)
+    )
+    /
+
+    {:ok, lib_pid} = Wasmtime.load(%Wasmtime.FromBytes{bytes: lib})
+
+    {:ok, pid} =
+      Wasmtime.load(%Wasmtime.FromBytes{
+        bytes: app,
+        links: [{"lib", lib_pid}],
+        func_imports: [{fn x -> x + 1 end, [:i32], [:i32]}]
+      })
+
+    {:ok, [11]} = Wasmtime.call_func(pid, "run", [5])
+  end
+
+  test "link missing export" do
+    lib = ~S/
+    (module
+      (func (export "double") (param i32) (result i32)
+        local.get 0)
+    )
+    /
+
+    app = ~S/
+    (module
+      (import "lib" "triple" (func (param i32) (result i32)))
+    )
+    /
+
+    {:ok, lib_pid} = Wasmtime.load(%Wasmtime.FromBytes{bytes: lib})
+
+    {:error, "linked module \"lib\" has no export \"triple\""} =
+      Wasmtime.load(%Wasmtime.FromBytes{bytes: app, links: [{"lib", lib_pid}]})
+  end
+
+  test "module imports" do
+    mod = ~S/
+    (module
+      (import "env" "log" (func (param i32 i32)))
+      (import "env" "now" (func (result i64)))
+      (import "env" "memory" (memory 1 2))
+      (import "env" "base" (global i32))
+      (import "env" "counter" (global (mut i64)))
+      (import "env" "table" (table 1 funcref))
+    )
+    /
+
+    imports = [
+      {"env", "log", :func, {[:i32, :i32], []}},
+      {"env", "now", :func, {[], [:i64]}},
+      {"env", "memory", :memory, {1, 2}},
+      {"env", "base", :global, {:i32, :const}},
+      {"env", "counter", :global, {:i64, :var}},
+      {"env", "table", :table, {:func_ref, 1, nil}}
+    ]
+
+    {:ok, ^imports} = Wasmtime.Module.imports(%Wasmtime.FromBytes{bytes: mod})
+
+    {:ok, pid} =
+      Wasmtime.load(%Wasmtime.FromBytes{
+        bytes: mod,
+        func_imports: [
+          {fn _, _ -> nil end, [:i32, :i32], []},
+          {fn -> 0 end, [], [:i64]}
+        ],
+        memory_imports: [{1, 2}],
+        global_imports: [{:i32, :const, 0}, {:i64, :var, 0}],
+        table_imports: [{1, nil}]
+      })
+
+    {:ok, ^imports} = Wasmtime.Module.imports(pid)
+  end
+
+  test "module imports invalid bytes" do
+    {:error, _} = Wasmtime.Module.imports(%Wasmtime.FromBytes{bytes: "not wasm"})
+  end
+
+  test "describe exports" do
+    mod = ~S/
+    (module
+      (memory (export "memory") 1 16)
+      (global (export "counter") (mut i64) (i64.const 0))
+      (table (export "table") 2 funcref)
+      (func (export "add") (param i32 i32) (result i32)
+        local.get 0
+        local.get 1
+        i32.add)
+    )
+    /
+
+    exports = [
+      {"memory", :memory, %{min: 1, max: 16, shared: false, memory64: false}},
+      {"counter", :global, %{type: :i64, mutability: :var}},
+      {"table", :table, %{element: :func_ref, min: 2, max: nil}},
+      {"add", :func, %{params: [:i32, :i32], results: [:i32]}}
+    ]
+
+    {:ok, ^exports} = Wasmtime.describe(%Wasmtime.FromBytes{bytes: mod})
+
+    {:ok, pid} = Wasmtime.load(%Wasmtime.FromBytes{bytes: mod})
+    {:ok, ^exports} = Wasmtime.describe(pid)
+  end
+
+  test "validate" do
+    {:ok, bytes} = File.read("test/data/wasmapp/wasmapp_bg.wasm")
+    :ok = Wasmtime.validate(bytes)
+
+    {:error, %{offset: 0, message: _}} = Wasmtime.validate("not wasm")
+
+    # A function returning i64 from an i32 body.
+    invalid =
+      <<0, 97, 115, 109, 1, 0, 0, 0, 1, 5, 1, 96, 0, 1, 126, 3, 2, 1, 0, 10, 6, 1, 4, 0, 65, 0,
+        11>>
+
+    {:error, %{offset: offset, message: "type mismatch" <> _}} = Wasmtime.validate(invalid)
+    true = offset > 0
+  end
+
+  test "wat2wasm and wasm2wat" do
+    {:ok, wat} = File.read("test/data/adder.wat")
+    {:ok, bytes} = Wasmtime.wat2wasm(wat)
+    :ok = Wasmtime.validate(bytes)
+
+    {:ok, text} = Wasmtime.wasm2wat(bytes)
+    {:ok, ^bytes} = Wasmtime.wat2wasm(text)
+
+    {:ok, pid} = Wasmtime.load(%Wasmtime.FromBytes{bytes: bytes})
+    {:ok, [3]} = Wasmtime.call_func(pid, "add", [1, 2])
+  end
+
+  test "wat2wasm parse error" do
+    {:error, %{line: 2, column: 4, message: _}} = Wasmtime.wat2wasm("(module\n  (fun))")
+    {:error, _} = Wasmtime.wasm2wat("not wasm")
+  end
+
+  test "custom sections and function names" do
+    mod = ~S/
+    (module
+      (@custom "abi" "v1")
+      (@custom "abi" (after func) "v2")
+      (import "env" "log" (func $log (param i32)))
+      (func $add (export "add") (param i32 i32) (result i32)
+        local.get 0
+        local.get 1
+        i32.add)
+    )
+    /
+
+    {:ok, bytes} = Wasmtime.wat2wasm(mod)
+
+    {:ok, ["v1", "v2"]} =
+      Wasmtime.Module.custom_sections(%Wasmtime.FromBytes{bytes: bytes}, "abi")
+
+    {:ok, %{0 => "log", 1 => "add"}} =
+      Wasmtime.Module.function_names(%Wasmtime.FromBytes{bytes: bytes})
+
+    {:ok, pid} =
+      Wasmtime.load(%Wasmtime.FromBytes{
+        bytes: bytes,
+        func_imports: [{fn _ -> nil end, [:i32], []}]
+      })
+
+    {:ok, ["v1", "v2"]} = Wasmtime.Module.custom_sections(pid, "abi")
+    {:ok, []} = Wasmtime.Module.custom_sections(pid, "capabilities")
+  end
+
+  test "config feature toggles" do
+    mod = ~S/
+    (module
+      (memory 1)
+      (func (export "fill")
+        i32.const 0
+        i32.const 7
+        i32.const 16
+        memory.fill)
+    )
+    /
+
+    {:ok, pid} = Wasmtime.load(%Wasmtime.FromBytes{bytes: mod})
+    {:ok, []} = Wasmtime.call_func(pid, "fill")
+
+    config = %Wasmtime.Config{bulk_memory: false, reference_types: false}
+    {:error, _} = Wasmtime.load(%Wasmtime.FromBytes{bytes: mod, config: config})
+    {:error, _} = Wasmtime.validate(Wasmtime.wat2wasm(mod) |> elem(1), config)
+
+    {:error, {:invalid_config, :tail_call, true}} =
+      Wasmtime.load(%Wasmtime.FromBytes{bytes: mod, config: %Wasmtime.Config{tail_call: true}})
+
+    {:error, {:invalid_config, :relaxed_simd, true}} =
+      Wasmtime.validate(Wasmtime.wat2wasm(mod) |> elem(1), %Wasmtime.Config{relaxed_simd: true})
+  end
+
+  test "engine tuning config" do
+    config = %Wasmtime.Config{
+      static_memory_maximum_size: 0,
+      static_memory_guard_size: 65_536,
+      dynamic_memory_guard_size: 65_536,
+      dynamic_memory_reserved_for_growth: 0,
+      parallel_compilation: false,
+      cranelift_nan_canonicalization: true,
+      generate_address_map: false,
+      native_unwind_info: false,
+      cranelift_flags: [{"opt_level", "speed"}]
+    }
+
+    {:ok, pid} =
+      Wasmtime.load(%Wasmtime.FromFile{file_path: "test/data/adder.wat", config: config})
+
+    {:ok, [3]} = Wasmtime.call_func(pid, "add", [1, 2])
+
+    {:error, _} =
+      Wasmtime.load(%Wasmtime.FromFile{
+        file_path: "test/data/adder.wat",
+        config: %Wasmtime.Config{cranelift_flags: [{"not_a_flag", "true"}]}
+      })
+  end
+
+  test "deterministic config" do
+    mod = ~S/
+    (module
+      (import "wasmtime_ex" "random_bytes" (func $random_bytes (param i32 i32)))
+      (import "wasmtime_ex" "monotonic_now" (func $monotonic_now (result i64)))
+      (import "wasmtime_ex" "wall_now" (func $wall_now (result i64)))
+      (memory (export "memory") 1)
+      (func (export "sample") (result i64 i64 i64)
+        (call $random_bytes (i32.const 0) (i32.const 8))
+        (i64.load (i32.const 0))
+        (call $monotonic_now)
+        (call $wall_now))
+      (func (export "nan") (param f32 f32) (result i32)
+        (i32.reinterpret_f32 (f32.div (local.get 0) (local.get 1))))
+    )
+    /
+
+    config = %Wasmtime.Config{deterministic: true, deterministic_seed: 42}
+
+    samples =
+      for _ <- 1..2 do
+        {:ok, pid} =
+          Wasmtime.load(%Wasmtime.FromBytes{bytes: mod, builtins: true, config: config})
+
+        {:ok, first} = Wasmtime.call_func(pid, "sample")
+        {:ok, second} = Wasmtime.call_func(pid, "sample")
+        {:ok, [nan]} = Wasmtime.call_func(pid, "nan", [0.0, 0.0])
+        {first, second, nan}
+      end
+
+    [{[random, 1_000, 2_000], [next_random, 3_000, 4_000], 0x7FC00000}, same] = samples
+    ^same = Enum.at(samples, 0)
+    true = random != next_random
+
+    {:ok, pid} =
+      Wasmtime.load(%Wasmtime.FromBytes{
+        bytes: mod,
+        builtins: true,
+        config: %{config | deterministic_seed: 7}
+      })
+
+    {:ok, [other_random, 1_000, 2_000]} = Wasmtime.call_func(pid, "sample")
+    true = other_random != random
+
+    {:error, {:invalid_config, :threads, true}} =
+      Wasmtime.load(%Wasmtime.FromBytes{bytes: mod, config: %{config | threads: true}})
+  end
+
+  test "invalid config" do
+    {:error, {:invalid_config, :strategy, :lightbeam}} =
+      Wasmtime.load(%Wasmtime.FromFile{
+        file_path: "test/data/adder.wat",
+        config: %Wasmtime.Config{strategy: :lightbeam}
+      })
+
+    {:error, {:invalid_config, :cranelift_opt_level, "speed"}} =
+      Wasmtime.load(%Wasmtime.FromFile{
+        file_path: "test/data/adder.wat",
+        config: %Wasmtime.Config{cranelift_opt_level: "speed"}
+      })
+
+    {:error, {:invalid_config, :max_wasm_stack, 0}} =
+      Wasmtime.validate(<<0, 97, 115, 109, 1, 0, 0, 0>>, %Wasmtime.Config{max_wasm_stack: 0})
+
+    {:error, {:invalid_config, :simd, nil}} =
+      Wasmtime.describe(%Wasmtime.FromFile{
+        file_path: "test/data/adder.wat",
+        config: %Wasmtime.Config{simd: nil}
+      })
+  end
+
   test "call_func non existing function" do
     mod = ~S/
     (module